@@ -1,9 +1,7 @@
 use colored::*;
-use crc_any::CRCu16;
 use goblin::elf::program_header::*;
 use hidapi::{HidApi, HidDevice};
 
-use maplit::hashmap;
 use std::{
     fs::File,
     io::Read,
@@ -88,31 +86,32 @@ fn main() {
             "Searching".green().bold(),
         );
 
-        let mut device: Option<HidDevice> = None;
-
-        let vendor = hashmap! {
-            0x1D50 => vec![0x6110, 0x6112],
-            0x239A => vec![0x0035, 0x002D, 0x0015, 0x001B, 0xB000, 0x0024, 0x000F, 0x0013, 0x0021, 0x0022, 0x0031, 0x002B, 0x0037, 0x0035, 0x002F, 0x002B, 0x0033, 0x0034, 0x003D, 0x0018, 0x001C, 0x001E, 0x0027, 0x0022],
-            0x04D8 => vec![0xEDB3, 0xEDBE, 0xEF66],
-            0x2341 => vec![0x024E, 0x8053, 0x024D],
-            0x16D0 => vec![0x0CDA],
-            0x03EB => vec![0x2402],
-            0x2886 => vec![0x000D, 0x002F],
-            0x1B4F => vec![0x0D23, 0x0D22],
-            0x1209 => vec![0x4D44, 0x2017],
-        };
-
-        for device_info in api.device_list() {
-            if let Some(products) = vendor.get(&device_info.vendor_id()) {
-                if products.contains(&device_info.product_id()) {
-                    if let Ok(d) = device_info.open_device(&api) {
-                        device = Some(d);
-                        break;
-                    }
-                }
+        match hf2::open_first(&api, hf2::matching_devices(&api, None, None, None)) {
+            Ok((d, _)) => d,
+            Err(hf2::Error::PermissionDenied) => {
+                let (vid, pid) = hf2::matching_devices(&api, None, None, None)
+                    .next()
+                    .map(|device_info| (device_info.vendor_id(), device_info.product_id()))
+                    .unwrap_or_default();
+
+                println!(
+                    "    {} opening {:04x}:{:04x}, on linux add a udev rule such as",
+                    "Permission denied".red().bold(),
+                    vid,
+                    pid
+                );
+                println!(
+                    "      SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", MODE=\"0666\"",
+                    vid, pid
+                );
+                println!("    to /etc/udev/rules.d/99-hf2.rules and reload udev, see the readme");
+                std::process::exit(1);
             }
+            Err(e) => panic!(
+                "Are you sure device is plugged in and in bootloader mode?: {}",
+                e
+            ),
         }
-        device.expect("Are you sure device is plugged in and in bootloader mode?")
     };
 
     println!(
@@ -173,14 +172,8 @@ fn flash_elf(path: PathBuf, d: &HidDevice) {
     let mut buffer = vec![];
     file.read_to_end(&mut buffer).unwrap();
 
-    if let Ok(binary) = goblin::elf::Elf::parse(&buffer.as_slice()) {
-        let bininfo = hf2::bin_info(&d).expect("bin_info failed");
-
-        log::debug!("{:?}", bininfo);
-
-        if bininfo.mode != hf2::BinInfoMode::Bootloader {
-            let _ = hf2::start_flash(&d).expect("start_flash failed");
-        }
+    if let Ok(binary) = goblin::elf::Elf::parse(buffer.as_slice()) {
+        let mut flasher = hf2::Flasher::new(d).expect("bin_info failed");
 
         //todo this could send multiple binary sections..
         let flashed: u8 = binary
@@ -195,80 +188,24 @@ fn flash_elf(path: PathBuf, d: &HidDevice) {
                 );
 
                 let data = &buffer[(ph.p_offset as usize)..][..ph.p_filesz as usize];
-                flash(data, ph.p_paddr as u32, &bininfo, &d);
+                let stats = flasher
+                    .flash(ph.p_paddr as u32, data, false)
+                    .expect("flash failed");
+                println!("    {}", stats);
                 1
             })
             .sum();
 
         //only reset if we actually sent something
         if flashed > 0 {
-            let _ = hf2::reset_into_app(&d).expect("reset_into_app failed");
-        }
-    }
-}
-
-fn flash(binary: &[u8], address: u32, bininfo: &hf2::BinInfoResponse, d: &HidDevice) {
-    let mut binary = binary.to_owned();
-
-    //pad zeros to page size
-    let padded_num_pages = (binary.len() as f64 / f64::from(bininfo.flash_page_size)).ceil() as u32;
-    let padded_size = padded_num_pages * bininfo.flash_page_size;
-    log::debug!(
-        "binary is {} bytes, padding to {} bytes",
-        binary.len(),
-        padded_size
-    );
-
-    for _i in 0..(padded_size as usize - binary.len()) {
-        binary.push(0x0);
-    }
-
-    // get checksums of existing pages
-    let top_address = address + padded_size as u32;
-    let max_pages = bininfo.max_message_size / 2 - 2;
-    let steps = max_pages * bininfo.flash_page_size;
-    let mut device_checksums = vec![];
-
-    for target_address in (address..top_address).step_by(steps as usize) {
-        let pages_left = (top_address - target_address) / bininfo.flash_page_size;
-
-        let num_pages = if pages_left < max_pages {
-            pages_left
-        } else {
-            max_pages
-        };
-        let chk =
-            hf2::checksum_pages(&d, target_address, num_pages).expect("checksum_pages failed");
-        device_checksums.extend_from_slice(&chk.checksums[..]);
-    }
-    log::debug!("checksums received {:04X?}", device_checksums);
-
-    // only write changed contents
-    for (page_index, page) in binary.chunks(bininfo.flash_page_size as usize).enumerate() {
-        let mut xmodem = CRCu16::crc16xmodem();
-
-        xmodem.digest(&page);
-
-        if xmodem.get_crc() != device_checksums[page_index] {
-            log::debug!(
-                "ours {:04X?} != {:04X?} theirs, updating page {}",
-                xmodem.get_crc(),
-                device_checksums[page_index],
-                page_index,
-            );
-
-            let target_address = address + bininfo.flash_page_size * page_index as u32;
-            let _ = hf2::write_flash_page(&d, target_address, page.to_vec())
-                .expect("write_flash_page failed");
-        } else {
-            log::debug!("not updating page {}", page_index,);
+            hf2::reset_into_app(d).expect("reset_into_app failed");
         }
     }
 }
 
 fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
-    if input.starts_with("0x") {
-        u16::from_str_radix(&input[2..], 16)
+    if let Some(hex) = input.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
     } else {
         input.parse::<u16>()
     }
@@ -281,18 +218,24 @@ struct Opt {
     bin: Option<String>,
     #[structopt(name = "example", long = "example")]
     example: Option<String>,
+    //only parsed so they're accepted, the raw args are handed to cargo build as they are
+    #[allow(dead_code)]
     #[structopt(name = "package", short = "p", long = "package")]
     package: Option<String>,
     #[structopt(name = "release", long = "release")]
     release: bool,
     #[structopt(name = "target", long = "target")]
     target: Option<String>,
+    #[allow(dead_code)]
     #[structopt(name = "PATH", long = "manifest-path", parse(from_os_str))]
     manifest_path: Option<PathBuf>,
+    #[allow(dead_code)]
     #[structopt(long)]
     no_default_features: bool,
+    #[allow(dead_code)]
     #[structopt(long)]
     all_features: bool,
+    #[allow(dead_code)]
     #[structopt(long)]
     features: Vec<String>,
 
@@ -0,0 +1,201 @@
+use crate::error::Error;
+use crate::progress::Progress;
+use crate::transport::Device;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+///A batch script, an ordered list of steps run against a single device connection.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    #[serde(rename = "step", default)]
+    pub steps: Vec<Step>,
+}
+
+///A single operation and whether a failure should stop the batch.
+#[derive(Debug, Deserialize)]
+pub struct Step {
+    #[serde(flatten)]
+    pub op: Op,
+    ///keep going with the next step if this one fails
+    #[serde(default)]
+    pub continue_on_error: bool,
+}
+
+//continue_on_error is taken by Step, anything else left over is a misspelt key
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Op {
+    ///write a binary at address, only pages that differ unless skip_checksum
+    Flash {
+        file: PathBuf,
+        address: u32,
+        #[serde(default)]
+        skip_checksum: bool,
+        #[serde(default)]
+        verify_each: bool,
+    },
+    ///compare a binary at address against the device checksums
+    Verify {
+        file: PathBuf,
+        address: u32,
+        #[serde(default)]
+        crc32: bool,
+    },
+    ///write consecutive words starting at address, chunked to fit max_message_size, with verify read back and compared
+    WriteWords {
+        address: u32,
+        words: Vec<u32>,
+        #[serde(default)]
+        verify: bool,
+    },
+    Info,
+    Bininfo,
+    Dmesg,
+    ResetIntoApp,
+    ResetIntoBootloader,
+}
+
+pub fn batch(
+    script: PathBuf,
+    d: &Device,
+    progress: &mut Progress,
+    words_per_request: Option<u32>,
+) -> Result<(), Error> {
+    let contents = std::fs::read_to_string(&script).map_err(Error::file(&script))?;
+    let script: Script = toml::from_str(&contents)
+        .map_err(|e| format!("couldn't parse script {:?}: {}", script, e))?;
+
+    let mut failed = 0;
+
+    for (index, step) in script.steps.iter().enumerate() {
+        status!("step {}: {:?}", index + 1, step.op);
+
+        match run(&step.op, d, progress, words_per_request) {
+            Ok(()) => status!("step {}: ok", index + 1),
+            Err(e) => {
+                println!("step {}: failed {}", index + 1, e);
+                failed += 1;
+
+                if !step.continue_on_error {
+                    println!("stopping batch");
+                    break;
+                }
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} batch step(s) failed", failed).into());
+    }
+    Ok(())
+}
+
+fn run(
+    op: &Op,
+    d: &Device,
+    progress: &mut Progress,
+    words_per_request: Option<u32>,
+) -> Result<(), Error> {
+    match op {
+        Op::Flash {
+            file,
+            address,
+            skip_checksum,
+            verify_each,
+        } => crate::flash(
+            file.clone(),
+            Some(*address),
+            d,
+            *skip_checksum,
+            *verify_each,
+            None,
+            progress,
+        )
+        .map(|stats| status!("{}", stats)),
+        Op::Verify {
+            file,
+            address,
+            crc32,
+        } => crate::verify(file.clone(), Some(*address), d, *crc32, words_per_request),
+        Op::WriteWords {
+            address,
+            words,
+            verify,
+        } => {
+            let max = hf2::max_words_per_write(&hf2::bin_info(d)?)?;
+            let words_per_request = crate::clamp_words_per_request(words_per_request, max);
+
+            Ok(hf2::write_memory(
+                d,
+                *address,
+                words,
+                words_per_request,
+                *verify,
+            )?)
+        }
+        Op::Info => Ok(hf2::info(d).map(|info| println!("{:?}", info))?),
+        Op::Bininfo => Ok(hf2::bin_info(d).map(|bininfo| println!("{:?}", bininfo))?),
+        Op::Dmesg => Ok(hf2::dmesg(d).map(|dmesg| println!("{:?}", dmesg))?),
+        Op::ResetIntoApp => Ok(hf2::reset_into_app(d)?),
+        Op::ResetIntoBootloader => Ok(hf2::reset_into_bootloader(d)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_script() {
+        let script: Script = toml::from_str(
+            r#"
+            [[step]]
+            op = "flash"
+            file = "app.bin"
+            address = 0x4000
+            verify_each = true
+
+            [[step]]
+            op = "write_words"
+            address = 0x2000_0000
+            words = [1, 2]
+            continue_on_error = true
+
+            [[step]]
+            op = "reset_into_app"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(script.steps.len(), 3);
+        assert!(matches!(
+            &script.steps[0].op,
+            Op::Flash {
+                address: 0x4000,
+                skip_checksum: false,
+                verify_each: true,
+                ..
+            }
+        ));
+        assert!(!script.steps[0].continue_on_error);
+        assert!(matches!(&script.steps[1].op, Op::WriteWords { words, .. } if words == &[1, 2]));
+        assert!(script.steps[1].continue_on_error);
+        assert!(matches!(script.steps[2].op, Op::ResetIntoApp));
+    }
+
+    #[test]
+    fn parse_malformed_script() {
+        //flash without its address
+        assert!(toml::from_str::<Script>("[[step]]\nop = \"flash\"\nfile = \"app.bin\"").is_err());
+        //an op that doesn't exist
+        assert!(toml::from_str::<Script>("[[step]]\nop = \"format\"").is_err());
+        //a key outside of any step
+        assert!(toml::from_str::<Script>("steps = []").is_err());
+        //a misspelt key in a step
+        assert!(toml::from_str::<Script>(
+            "[[step]]\nop = \"verify\"\nfile = \"app.bin\"\naddress = 0\ncrc = true"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::Path;
+
+///Contents of an image, mapped straight from the file where that's possible so the memory in use doesn't grow with the image. Pages are sliced out of it, only the padded last page is copied.
+pub enum Binary {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Binary {
+    ///Map path, or read it whole when it isn't a regular file, like a pipe, or is empty, which can't be mapped. A path of - reads stdin to the end, pages are only padded once it's all in.
+    pub fn open(path: &Path) -> io::Result<Binary> {
+        if is_stdin(path) {
+            return Binary::read(io::stdin().lock());
+        }
+
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+
+        if metadata.is_file() && metadata.len() > 0 {
+            //the file changing underneath us while flashing is no different from it changing just before
+            let map = unsafe { Mmap::map(&file)? };
+            return Ok(Binary::Mapped(map));
+        }
+
+        Binary::read(file)
+    }
+
+    pub fn read(mut source: impl Read) -> io::Result<Binary> {
+        let mut contents = vec![];
+        source.read_to_end(&mut contents)?;
+        Ok(Binary::Read(contents))
+    }
+}
+
+///Whether path is -, standing for stdin.
+pub fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+impl From<Vec<u8>> for Binary {
+    fn from(contents: Vec<u8>) -> Self {
+        Binary::Read(contents)
+    }
+}
+
+impl Deref for Binary {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Binary::Mapped(map) => map,
+            Binary::Read(contents) => contents,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Binary {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
@@ -0,0 +1,216 @@
+use crate::error::Error;
+use hidapi::{DeviceInfo, HidApi, HidDevice};
+use std::time::{Duration, Instant};
+
+///BININFO round trips averaged for the latency check.
+const LATENCY_ROUNDS: u32 = 10;
+///Average round trip above which flashing will feel sluggish.
+const SLOW_LATENCY: Duration = Duration::from_millis(20);
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Pass,
+    Fail,
+    ///an earlier check failed so this one couldn't run
+    Skipped,
+}
+
+///Outcome of a single doctor check, with a suggestion when it didn't pass.
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: String) -> Self {
+        Check {
+            name,
+            status: Status::Pass,
+            detail,
+            hint: None,
+        }
+    }
+
+    fn fail(name: &'static str, detail: String, hint: String) -> Self {
+        Check {
+            name,
+            status: Status::Fail,
+            detail,
+            hint: Some(hint),
+        }
+    }
+
+    fn skipped(name: &'static str) -> Self {
+        Check {
+            name,
+            status: Status::Skipped,
+            detail: "earlier check failed".into(),
+            hint: None,
+        }
+    }
+}
+
+///Run every check in order against the default device, or vid/pid if given, and print a checklist.
+pub fn doctor(
+    api: &HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+) -> Result<(), Error> {
+    let mut checks = vec![];
+
+    let (check, candidates) = enumerate(api, vid, pid, serial);
+    checks.push(check);
+
+    let d = if candidates.is_empty() {
+        checks.push(Check::skipped("open"));
+        None
+    } else {
+        let (check, d) = open(api, &candidates);
+        checks.push(check);
+        d
+    };
+
+    match d {
+        Some(d) => {
+            checks.push(bininfo(&d));
+            checks.push(latency(&d));
+        }
+        None => {
+            checks.push(Check::skipped("bininfo"));
+            checks.push(Check::skipped("latency"));
+        }
+    }
+
+    for check in &checks {
+        let status = match check.status {
+            Status::Pass => "pass",
+            Status::Fail => "FAIL",
+            Status::Skipped => "skip",
+        };
+        println!("[{}] {}: {}", status, check.name, check.detail);
+
+        if let Some(hint) = &check.hint {
+            println!("       {}", hint);
+        }
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    if failed > 0 {
+        return Err(format!("{} of {} checks failed", failed, checks.len()).into());
+    }
+    Ok(())
+}
+
+///Does anything enumerate that we'd try to open.
+fn enumerate<'a>(
+    api: &'a HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&'a str>,
+) -> (Check, Vec<&'a DeviceInfo>) {
+    let candidates: Vec<&DeviceInfo> = hf2::matching_devices(api, vid, pid, serial).collect();
+
+    let check = match candidates.first() {
+        Some(device_info) => Check::pass(
+            "enumerate",
+            format!(
+                "found {} device(s), first {:04x}:{:04x} {:?}",
+                candidates.len(),
+                device_info.vendor_id(),
+                device_info.product_id(),
+                device_info.product_string().unwrap_or_default()
+            ),
+        ),
+        None => Check::fail(
+            "enumerate",
+            match (vid, pid, serial) {
+                (Some(v), Some(p), None) => format!("no device with {:04x}:{:04x}", v, p),
+                (Some(v), Some(p), Some(serial)) => {
+                    format!("no device with {:04x}:{:04x} and serial {}", v, p, serial)
+                }
+                (_, _, Some(serial)) => format!("no known hf2 bootloader with serial {}", serial),
+                _ => "no known hf2 bootloader found".into(),
+            },
+            "put the board in bootloader mode, usually a double tap of reset, or pass --vid/--pid for a board we don't know".into(),
+        ),
+    };
+
+    (check, candidates)
+}
+
+///Can we actually open one of them, the usual failure is permissions.
+fn open(api: &HidApi, candidates: &[&DeviceInfo]) -> (Check, Option<HidDevice>) {
+    let error = match hf2::open_first(api, candidates.iter().copied()) {
+        Ok((d, device_info)) => {
+            let check = Check::pass(
+                "open",
+                format!("opened {:?}", device_info.path().to_string_lossy()),
+            );
+            return (check, Some(d));
+        }
+        Err(e) => e,
+    };
+
+    let hint = match error {
+        hf2::Error::PermissionDenied => {
+            crate::permission_hint(candidates[0].vendor_id(), candidates[0].product_id())
+        }
+        _ => "make sure no other program has the device open, or try replugging it".into(),
+    };
+
+    let check = Check::fail("open", format!("couldn't open: {}", error), hint);
+    (check, None)
+}
+
+///Does the bootloader answer the most basic command.
+fn bininfo(d: &HidDevice) -> Check {
+    match hf2::bin_info(d) {
+        Ok(bininfo) => Check::pass(
+            "bininfo",
+            format!(
+                "{:?} mode, {:?}, {} pages of {} bytes",
+                bininfo.mode, bininfo.family_id, bininfo.flash_num_pages, bininfo.flash_page_size
+            ),
+        ),
+        Err(e) => Check::fail(
+            "bininfo",
+            format!("no response: {}", e),
+            "the device may be running its app rather than the bootloader, try reset-into-bootloader or a double tap of reset".into(),
+        ),
+    }
+}
+
+///Average BININFO round trip.
+fn latency(d: &HidDevice) -> Check {
+    let instant = Instant::now();
+
+    for _ in 0..LATENCY_ROUNDS {
+        if let Err(e) = hf2::bin_info(d) {
+            return Check::fail(
+                "latency",
+                format!("bininfo failed mid measurement: {}", e),
+                "a flaky cable or hub can drop reports, try a different port".into(),
+            );
+        }
+    }
+
+    let average = instant.elapsed() / LATENCY_ROUNDS;
+    let detail = format!("{:.1}ms average round trip", average.as_secs_f64() * 1000.0);
+
+    if average > SLOW_LATENCY {
+        Check::fail(
+            "latency",
+            detail,
+            "slow round trips usually mean a hub or vm usb passthrough, try a port directly on the host".into(),
+        )
+    } else {
+        Check::pass("latency", detail)
+    }
+}
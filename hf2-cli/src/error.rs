@@ -0,0 +1,109 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+///Everything a command can fail with, printed as a single message by main instead of panicking.
+#[derive(Debug)]
+pub enum Error {
+    ///a command failed on the device, or the device dropped off mid command
+    Device(hf2::Error),
+    ///couldn't read or write a file of ours
+    File {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Hid(hidapi::HidError),
+    ///nothing enumerated matching any of these vid/pid pairs, and serial when given
+    NotFound {
+        searched: Vec<(u16, u16)>,
+        serial: Option<String>,
+    },
+    PermissionDenied {
+        vid: u16,
+        pid: u16,
+    },
+    ///bad arguments only caught at runtime, or a failure already explained on stdout
+    Message(String),
+}
+
+impl Error {
+    ///For map_err, attaches the path a file operation failed on.
+    pub fn file(path: &Path) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |source| Error::File {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    ///For hf2 hooks like a Flasher's reopen, which fail with an hf2::Error. The device's own errors are passed through as they were.
+    pub fn into_device(self) -> hf2::Error {
+        match self {
+            Error::Device(e) => e,
+            e => hf2::Error::Transmission(Box::new(e)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Device(e) => write!(f, "device command failed: {}", e),
+            Error::File { path, source } => write!(f, "{:?}: {}", path, source),
+            Error::Hid(e) => write!(f, "hid: {}", e),
+            Error::NotFound { searched, serial } => {
+                match serial {
+                    Some(serial) => write!(f, "no device found with serial {}", serial)?,
+                    None => write!(f, "no device found")?,
+                }
+                write!(f, ", is it plugged in and in bootloader mode? searched")?;
+                //one line per vendor, searched is sorted so they're consecutive
+                let mut last_vid = None;
+                for (vid, pid) in searched {
+                    if last_vid != Some(vid) {
+                        write!(f, "\n  {:04x}:", vid)?;
+                        last_vid = Some(vid);
+                    }
+                    write!(f, " {:04x}", pid)?;
+                }
+                Ok(())
+            }
+            Error::PermissionDenied { vid, pid } => write!(
+                f,
+                "permission denied opening {:04x}:{:04x}\n{}",
+                vid,
+                pid,
+                crate::permission_hint(*vid, *pid)
+            ),
+            Error::Message(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Device(e) => Some(e),
+            Error::File { source, .. } => Some(source),
+            Error::Hid(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<hf2::Error> for Error {
+    fn from(e: hf2::Error) -> Self {
+        Error::Device(e)
+    }
+}
+
+impl From<hidapi::HidError> for Error {
+    fn from(e: hidapi::HidError) -> Self {
+        Error::Hid(e)
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Message(message)
+    }
+}
@@ -0,0 +1,105 @@
+use crate::error::Error;
+use crate::progress::Progress;
+use crate::transport::Device;
+use hidapi::HidApi;
+use std::path::Path;
+use std::sync::Mutex;
+
+///A device to flash and the name to report it under, its serial when it has one.
+struct Target {
+    label: String,
+    d: Device,
+}
+
+///Flash the same image to every matching device, up to jobs at a time, and print a per device summary. A failure on one board doesn't stop the others.
+#[allow(clippy::too_many_arguments)]
+pub fn flash_all(
+    api: &HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    report_size: usize,
+    timeout: Option<u64>,
+    file: &Path,
+    address: Option<u32>,
+    skip_checksum: bool,
+    jobs: Option<usize>,
+) -> Result<(), Error> {
+    let mut targets = vec![];
+
+    for device_info in hf2::matching_devices(api, vid, pid, serial) {
+        let label = match device_info.serial_number() {
+            Some(serial) if !serial.is_empty() => serial.to_string(),
+            _ => device_info.path().to_string_lossy().into_owned(),
+        };
+
+        match hf2::open_device(api, device_info) {
+            Ok(d) => targets.push(Target {
+                label,
+                d: crate::with_report_size(d, report_size, timeout)?,
+            }),
+            Err(e) => println!("[{}] couldn't open: {}, skipping", label, e),
+        }
+    }
+
+    if targets.is_empty() {
+        return Err("no devices to flash".to_string().into());
+    }
+
+    let jobs = jobs.unwrap_or(targets.len()).clamp(1, targets.len());
+    status!("flashing {} devices, {} at a time", targets.len(), jobs);
+
+    let queue = Mutex::new(targets);
+    let results = Mutex::new(vec![]);
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let target = match queue.lock().unwrap().pop() {
+                    Some(target) => target,
+                    None => break,
+                };
+
+                status!("[{}] flashing", target.label);
+
+                let result = crate::flash(
+                    file.to_path_buf(),
+                    address,
+                    &target.d,
+                    skip_checksum,
+                    false,
+                    Some(&target.label),
+                    &mut Progress::default(),
+                )
+                .and_then(|stats| {
+                    hf2::reset_into_app(&target.d)?;
+                    Ok(stats)
+                });
+
+                match &result {
+                    Ok(stats) => status!("[{}] {}", target.label, stats),
+                    Err(e) => println!("[{}] failed {}", target.label, e),
+                }
+
+                results.lock().unwrap().push((target.label, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by(|a, b| a.0.cmp(&b.0));
+
+    status!("summary");
+    for (label, result) in &results {
+        match result {
+            Ok(stats) => status!("  {} ok, {} pages written", label, stats.pages_written),
+            Err(e) => println!("  {} FAILED {}", label, e),
+        }
+    }
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} devices failed", failed, results.len()).into());
+    }
+    Ok(())
+}
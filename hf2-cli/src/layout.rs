@@ -0,0 +1,116 @@
+use crate::error::Error;
+use crate::transport::Device;
+use crate::Format;
+use serde::Serialize;
+
+///A named range of flash.
+#[derive(Serialize)]
+struct Region {
+    name: &'static str,
+    start: u32,
+    end: u32,
+}
+
+#[derive(Serialize)]
+struct Layout {
+    model: Option<String>,
+    board_id: Option<String>,
+    flash_base: u32,
+    flash_size: u32,
+    flash_page_size: u32,
+    flash_num_pages: u32,
+    regions: Vec<Region>,
+}
+
+///Print the device's flash as bininfo reports it, split into bootloader and app at app_address when that's known for the board.
+pub fn layout(d: &Device, app_address: Option<u32>, format: Format) -> Result<(), Error> {
+    let bininfo = hf2::bin_info(d)?;
+
+    //only for naming the board, plenty of bootloaders don't answer info
+    let info = match hf2::info(d) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            log::debug!("no info for layout {:?}", e);
+            None
+        }
+    };
+
+    let flash_base = bininfo.flash_base();
+    let flash_size = bininfo
+        .flash_page_size
+        .checked_mul(bininfo.flash_num_pages)
+        .filter(|size| flash_base.checked_add(*size).is_some())
+        .ok_or_else(|| {
+            format!(
+                "bininfo's {} pages of {} bytes from 0x{:08X} run past the 32 bit address space",
+                bininfo.flash_num_pages, bininfo.flash_page_size, flash_base
+            )
+        })?;
+    let flash_end = flash_base + flash_size;
+
+    let regions = match app_address {
+        Some(app) if (flash_base..flash_end).contains(&app) => vec![
+            Region {
+                name: "bootloader",
+                start: flash_base,
+                end: app,
+            },
+            Region {
+                name: "app",
+                start: app,
+                end: flash_end,
+            },
+        ],
+        _ => vec![],
+    };
+
+    let layout = Layout {
+        model: info.as_ref().and_then(|info| info.model.clone()),
+        board_id: info.as_ref().and_then(|info| info.board_id.clone()),
+        flash_base,
+        flash_size,
+        flash_page_size: bininfo.flash_page_size,
+        flash_num_pages: bininfo.flash_num_pages,
+        regions,
+    };
+
+    if format == Format::Json {
+        return crate::print_json(&layout);
+    }
+
+    if let Some(model) = &layout.model {
+        println!("{:<12}{}", "model", model);
+    }
+    if let Some(board_id) = &layout.board_id {
+        println!("{:<12}{}", "board", board_id);
+    }
+    println!(
+        "{:<12}0x{:08X}..0x{:08X}, {} KB",
+        "flash",
+        flash_base,
+        flash_end,
+        flash_size / 1024
+    );
+    println!(
+        "{:<12}{} x {} bytes",
+        "pages", layout.flash_num_pages, layout.flash_page_size
+    );
+
+    for region in &layout.regions {
+        print!(
+            "{:<12}0x{:08X}..0x{:08X}, {} KB",
+            region.name,
+            region.start,
+            region.end,
+            (region.end - region.start) / 1024
+        );
+        if region.name == "app" {
+            print!(", flash with --address 0x{:X}", region.start);
+        }
+        println!();
+    }
+    if layout.regions.is_empty() {
+        println!("where the bootloader ends isn't known for this board");
+    }
+    Ok(())
+}
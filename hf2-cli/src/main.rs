@@ -1,239 +1,1514 @@
-use crc_any::CRCu16;
-
 use hidapi::{HidApi, HidDevice};
-use maplit::hashmap;
+use serde::Serialize;
+use std::ffi::CString;
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
-fn main() {
-    pretty_env_logger::init();
+//status! is used by the modules after it
+#[macro_use]
+mod output;
+
+mod batch;
+mod binary;
+mod doctor;
+mod error;
+mod flashall;
+mod layout;
+mod manifest;
+mod options;
+mod progress;
+mod repeat;
+mod transport;
+mod watch;
+
+use binary::Binary;
+use error::Error;
+use progress::{Bar, Progress};
+use transport::{Device, Location, Transport};
 
+fn main() {
     let args = Opt::from_args();
+    output::init(args.verbose, args.quiet);
 
-    let api = HidApi::new().expect("Couldn't find system usb");
+    if let Err(e) = run(args) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    let d = if let (Some(v), Some(p)) = (args.vid, args.pid) {
-        api.open(v, p)
-            .expect("Are you sure device is plugged in and in bootloader mode?")
-    } else {
-        println!("no vid/pid provided..");
-
-        let mut device: Option<HidDevice> = None;
-
-        let vendor = hashmap! {
-            0x1D50 => vec![0x6110, 0x6112],
-            0x239A => vec![0x0035, 0x002D, 0x0015, 0x001B, 0xB000, 0x0024, 0x000F, 0x0013, 0x0021, 0x0022, 0x0031, 0x002B, 0x0037, 0x0035, 0x002F, 0x002B, 0x0033, 0x0034, 0x003D, 0x0018, 0x001C, 0x001E, 0x0027, 0x0022],
-            0x04D8 => vec![0xEDB3, 0xEDBE, 0xEF66],
-            0x2341 => vec![0x024E, 0x8053, 0x024D],
-            0x16D0 => vec![0x0CDA],
-            0x03EB => vec![0x2402],
-            0x2886 => vec![0x000D, 0x002F],
-            0x1B4F => vec![0x0D23, 0x0D22],
-            0x1209 => vec![0x4D44, 0x2017],
-        };
+fn run(args: Opt) -> Result<(), Error> {
+    let mut progress = Progress::from_fd(args.progress_fd)
+        .map_err(|e| format!("--progress-fd: {}, it's only supported on unix", e))?;
+
+    let mut api = HidApi::new()?;
+
+    //has to run exactly when opening the default device would fail
+    if let Cmd::doctor = &args.cmd {
+        return doctor::doctor(&api, args.vid, args.pid, args.serial.as_deref());
+    }
+
+    //a board that was just power cycled may not have enumerated yet, a serial port is opened by name
+    if let (Some(wait), None) = (args.wait, &args.port) {
+        let timeout = seconds(wait)?;
+        match hf2::wait_for_device(
+            &mut api,
+            args.vid,
+            args.pid,
+            args.serial.as_deref(),
+            timeout,
+        ) {
+            //opening reports it along with what was searched for
+            Ok(()) | Err(hf2::Error::NotFound) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    //only enumerates, never opens
+    if let Cmd::list = &args.cmd {
+        return list(
+            &api,
+            args.vid,
+            args.pid,
+            args.serial.as_deref(),
+            args.format,
+        );
+    }
+
+    //follows the device as it disappears and comes back
+    if let Cmd::watchMode { interval } = &args.cmd {
+        watch::watch_mode(
+            &mut api,
+            args.vid,
+            args.pid,
+            args.serial.as_deref(),
+            Duration::from_millis(*interval),
+        );
+    }
+
+    //opens every matching device rather than the single default one
+    if let Cmd::flashAll {
+        file,
+        address,
+        skip_checksum,
+        jobs,
+    } = &args.cmd
+    {
+        return flashall::flash_all(
+            &api,
+            args.vid,
+            args.pid,
+            args.serial.as_deref(),
+            args.report_size,
+            args.timeout,
+            file,
+            *address,
+            *skip_checksum,
+            *jobs,
+        );
+    }
+
+    //opens its own devices by serial rather than the single default one
+    if let Cmd::compareDevices {
+        serial,
+        address,
+        length,
+    } = &args.cmd
+    {
+        return compare_devices(&api, args.vid, args.pid, serial, *address, *length);
+    }
+
+    let capture = match &args.capture {
+        Some(path) => Some(File::create(path).map_err(Error::file(path))?),
+        None => None,
+    };
 
-        for device_info in api.device_list() {
-            if let Some(products) = vendor.get(&device_info.vendor_id()) {
-                if products.contains(&device_info.product_id()) {
-                    if let Ok(d) = device_info.open_device(&api) {
-                        device = Some(d);
-                        break;
+    //keep stdout to the json alone
+    let human = args.format == Format::Human;
+
+    if human && (args.vid.is_none() || args.pid.is_none()) {
+        status!("no vid/pid provided..");
+    }
+
+    let (d, path) = match &args.port {
+        Some(port) => {
+            let d = hf2::SerialTransport::open(port)?;
+            (Transport::from(d), Location::Port(port.clone()))
+        }
+        None => {
+            let (d, path) = open_device(&api, args.vid, args.pid, args.serial.as_deref())?;
+            (Transport::from(d), Location::Hid(path))
+        }
+    };
+    let d = wrap(d, args.report_size, args.timeout, capture.as_ref())?;
+
+    if human {
+        status!("found {}", d.describe());
+    }
+
+    //following the device across a reset means finding it again on the bus
+    let on_port = args.port.is_some();
+    let hid_only = |flag: &str| -> Result<(), Error> {
+        if on_port {
+            return Err(format!("{} doesn't work with --port", flag).into());
+        }
+        Ok(())
+    };
+
+    match args.cmd {
+        Cmd::resetIntoApp { wait: None } => hf2::reset_into_app(&d)?,
+        Cmd::resetIntoBootloader { wait: None } => hf2::reset_into_bootloader(&d)?,
+        Cmd::resetIntoApp { wait: Some(wait) } => {
+            hid_only("--wait")?;
+            let mode = hf2::BinInfoMode::User;
+            let serial = args.serial.as_deref();
+            reset_and_wait(
+                &mut api,
+                &d,
+                args.vid,
+                args.pid,
+                serial,
+                mode,
+                seconds(wait)?,
+            )?;
+            status!("back in app");
+        }
+        Cmd::resetIntoBootloader { wait: Some(wait) } => {
+            hid_only("--wait")?;
+            let mode = hf2::BinInfoMode::Bootloader;
+            let serial = args.serial.as_deref();
+            reset_and_wait(
+                &mut api,
+                &d,
+                args.vid,
+                args.pid,
+                serial,
+                mode,
+                seconds(wait)?,
+            )?;
+            status!("back in bootloader");
+        }
+        Cmd::info => info(&d, args.format)?,
+        Cmd::bininfo => bininfo(&d, args.format)?,
+        Cmd::layout => layout::layout(&d, app_address(&api, &path), args.format)?,
+        Cmd::dmesg {
+            strict,
+            follow: false,
+            ..
+        } => dmesg(&d, strict)?,
+        Cmd::dmesg {
+            strict,
+            follow: true,
+            interval,
+        } => dmesg_follow(&d, strict, Duration::from_millis(interval))?,
+        Cmd::flash {
+            file,
+            address,
+            skip_checksum,
+            force_all,
+            dry_run,
+            reopen,
+            auto_bootloader,
+            bootloader_timeout,
+            require_model,
+            require_board_id,
+            exact,
+            verify_each,
+            verify_after,
+            batch,
+            force,
+            manifest,
+            manifest_append,
+            check_vectors,
+            ram_start,
+            ram_end,
+            strict,
+            repeat,
+            allow_overlap,
+            no_reset,
+        } => {
+            if repeat {
+                hid_only("--repeat")?;
+                if args.serial.is_some() {
+                    return Err("--repeat flashes whichever board comes next, it can't be limited to a --serial"
+                        .to_string()
+                        .into());
+                }
+                //stdin can only be read once, there'd be nothing left for the next board
+                if binary::is_stdin(&file) {
+                    return Err("--repeat needs a file, not stdin".to_string().into());
+                }
+                if manifest.is_some() && !manifest_append {
+                    return Err("--repeat needs --manifest-append, each board would overwrite the last one's manifest"
+                        .to_string()
+                        .into());
+                }
+            }
+
+            let mut board = (d, path);
+            let mut flashed = 0;
+
+            loop {
+                let (d, path) = board;
+                let (d, path) = if auto_bootloader {
+                    hid_only("--auto-bootloader")?;
+                    let timeout = Duration::from_millis(bootloader_timeout);
+                    match enter_bootloader(
+                        &mut api,
+                        &d,
+                        args.vid,
+                        args.pid,
+                        args.serial.as_deref(),
+                        timeout,
+                    )? {
+                        Some((hid, path)) => {
+                            drop(d);
+                            (
+                                wrap(hid, args.report_size, args.timeout, capture.as_ref())?,
+                                Location::Hid(path),
+                            )
+                        }
+                        None => (d, path),
                     }
+                } else {
+                    (d, path)
+                };
+
+                //stdin can only be read once, there'd be nothing left to hash
+                if manifest.is_some() && binary::is_stdin(&file) {
+                    return Err("--manifest needs a file, not stdin".to_string().into());
                 }
+
+                //refuse before a single page is written
+                require_info(&d, "Model", require_model.clone(), exact)?;
+                require_info(&d, "Board-ID", require_board_id.clone(), exact)?;
+
+                let address = known_address(&api, address, &file, &path);
+                let image = read_image(&file, address)?;
+
+                if check_vectors.is_some() {
+                    let (address, binary) = &image.segments[0];
+                    vectors(binary, *address, ram_start..ram_end, strict)?;
+                }
+
+                //a forced flash isn't left flying blind, what it wrote is checked afterwards
+                let options = FlashOptions {
+                    skip_checksum: skip_checksum || force_all,
+                    force,
+                    allow_overlap,
+                    label: None,
+                };
+                let config = hf2::FlasherConfig {
+                    batch_pages: batch,
+                    verify_each,
+                    //the manifest records whether it passed
+                    verify_after: verify_after || force_all || manifest.is_some(),
+                    ..Default::default()
+                };
+
+                let sha256 = image.sha256.clone();
+                //a uf2 or hex carries its own addresses, --address alone would record 0
+                let lowest_address = image
+                    .segments
+                    .iter()
+                    .map(|(address, _)| *address)
+                    .min()
+                    .unwrap_or_default();
+
+                if dry_run {
+                    return dry_run_image(image, &d, &options);
+                }
+
+                //some drivers get into a bad state after a long run of commands, start the flash on a fresh handle
+                let d = if reopen {
+                    drop(d);
+                    let d = path.open(&api)?;
+                    wrap(d, args.report_size, args.timeout, capture.as_ref())?
+                } else {
+                    d
+                };
+
+                //found again the way it was first, its path can change when it comes back on the bus
+                let (report_size, timeout) = (args.report_size, args.timeout);
+                let (vid, pid) = (args.vid, args.pid);
+                let serial = args
+                    .serial
+                    .clone()
+                    .or_else(|| d.serial_number().filter(|s| !s.is_empty()));
+                let mut moved_to = None;
+                let reopen_device: Reopen = Box::new(|| {
+                    let (d, location) = reopen_stale(&mut api, &path, vid, pid, serial.as_deref())
+                        .map_err(Error::into_device)?;
+                    moved_to = Some(location);
+                    wrap(d, report_size, timeout, capture.as_ref()).map_err(Error::into_device)
+                });
+
+                //a flash that failed verify_after returns its error before any manifest is written
+                let verified = config.verify_after;
+                let (stats, reopened) = flash_image(
+                    image,
+                    &d,
+                    config,
+                    &options,
+                    Some(reopen_device),
+                    &mut progress,
+                )?;
+                status!("{}", stats);
+
+                //the handle the flash started on is dead once it had to reopen
+                let (d, path) = match (reopened, moved_to) {
+                    (Some(reopened), Some(location)) => (reopened, location),
+                    _ => (d, path),
+                };
+
+                if let Some(manifest) = &manifest {
+                    manifest::Manifest::new(
+                        &d,
+                        file.clone(),
+                        sha256,
+                        lowest_address,
+                        stats,
+                        verified,
+                    )
+                    .write(manifest, manifest_append)?;
+                }
+
+                //left in the bootloader for another region or a debugger
+                if !no_reset {
+                    hf2::reset_into_app(&d)?;
+                }
+
+                if !repeat {
+                    break;
+                }
+
+                flashed += 1;
+                status!("{} flashed", flashed);
+
+                //only hid gets this far, --repeat is refused on a port
+                let flashed_serial = d.serial_number().filter(|s| !s.is_empty());
+                drop(d);
+                let (hid, path) = match path {
+                    Location::Hid(path) => repeat::next_board(
+                        &mut api,
+                        args.vid,
+                        args.pid,
+                        &path,
+                        flashed_serial.as_deref(),
+                    ),
+                    Location::Port(_) => unreachable!(),
+                };
+                board = (
+                    wrap(hid, args.report_size, args.timeout, capture.as_ref())?,
+                    Location::Hid(path),
+                );
             }
         }
-        device.expect("Are you sure device is plugged in and in bootloader mode?")
-    };
+        Cmd::verify {
+            file,
+            address,
+            crc32,
+        } => {
+            let address = known_address(&api, address, &file, &path);
+            verify(file, address, &d, crc32, args.words_per_request)?
+        }
+        Cmd::compare {
+            file,
+            address,
+            limit,
+        } => {
+            let address = known_address(&api, address, &file, &path);
+            compare(file, address, limit, &d, args.words_per_request)?
+        }
+        Cmd::imageCrc { file, address } => {
+            let address = known_address(&api, address, &file, &path);
+            image_crc(file, address, &d, args.words_per_request)?
+        }
+        Cmd::occupied { address, pages } => occupied(address, pages, &d)?,
+        Cmd::checksum { address, pages } => checksum(address, pages, &d)?,
+        Cmd::erase { address, length } => {
+            let pages = hf2::erase_region(&d, address, length)?;
+            status!("erased {} pages from 0x{:08X}", pages, address);
+        }
+        Cmd::peek { address } => peek(address, &d)?,
+        Cmd::poke {
+            address,
+            value,
+            verify,
+        } => poke(address, value, verify, &d)?,
+        Cmd::dump {
+            file,
+            address,
+            length,
+            format,
+        } => dump(file, address, length, &format, &d, args.words_per_request)?,
+        Cmd::batch { script } => batch::batch(script, &d, &mut progress, args.words_per_request)?,
+        Cmd::options { address, words } => options::options(&d, address, words)?,
+        Cmd::compareDevices { .. }
+        | Cmd::doctor
+        | Cmd::list
+        | Cmd::watchMode { .. }
+        | Cmd::flashAll { .. } => {
+            unreachable!()
+        }
+    }
+    Ok(())
+}
 
-    println!(
-        "found {:?} {:?}",
-        d.get_manufacturer_string(),
-        d.get_product_string()
-    );
+fn info(d: &Device, format: Format) -> Result<(), Error> {
+    let info = hf2::info(d)?;
+    if format == Format::Json {
+        return print_json(&info);
+    }
 
-    match args.cmd {
-        Cmd::resetIntoApp => hf2::reset_into_app(&d).unwrap(),
-        Cmd::resetIntoBootloader => hf2::reset_into_bootloader(&d).unwrap(),
-        Cmd::info => info(&d),
-        Cmd::bininfo => bininfo(&d),
-        Cmd::dmesg => dmesg(&d),
-        Cmd::flash { file, address, skip_checksum } => flash(file, address, &d, skip_checksum),
-        Cmd::verify { file, address } => verify(file, address, &d),
+    let known = [
+        ("Model", &info.model),
+        ("Board-ID", &info.board_id),
+        ("Bootloader", &info.bootloader),
+        ("Date", &info.date),
+    ];
+    let known: Vec<_> = known
+        .iter()
+        .filter_map(|(key, value)| value.as_ref().map(|value| (*key, value)))
+        .collect();
+
+    //nothing parsed, show it as the board wrote it
+    if known.is_empty() && info.raw.is_empty() {
+        println!("{}", info.info.trim_end());
+        return Ok(());
+    }
+
+    let raw = info.raw.iter().map(|(key, value)| (key.as_str(), value));
+    for (key, value) in known.into_iter().chain(raw) {
+        println!("{}: {}", key, value);
+    }
+    Ok(())
+}
+
+///Warn about, or with strict abort on, a Cortex-M vector table that doesn't point into ram and the flash being written.
+fn vectors(
+    image: &[u8],
+    address: u32,
+    ram: std::ops::Range<u32>,
+    strict: bool,
+) -> Result<(), Error> {
+    let flash = address..address.saturating_add(image.len() as u32);
+
+    let problems = hf2::check_cortex_m_vectors(image, ram, flash);
+
+    for problem in &problems {
+        eprintln!("warning: {}", problem);
+    }
+
+    if strict && !problems.is_empty() {
+        return Err("vector table looks mislinked, not flashing"
+            .to_string()
+            .into());
     }
+    Ok(())
 }
 
-fn info(d: &HidDevice) {
-    let info = hf2::info(&d).expect("info failed");
-    println!("{:?}", info);
+///Abort unless the info field key matches required, as a case insensitive substring or exactly.
+fn require_info(d: &Device, key: &str, required: Option<String>, exact: bool) -> Result<(), Error> {
+    let required = match required {
+        Some(required) => required,
+        None => return Ok(()),
+    };
+
+    let info = hf2::info(d)?;
+    let actual = info.field(key);
+
+    let matches = match actual {
+        Some(actual) if exact => actual == required,
+        Some(actual) => actual.to_lowercase().contains(&required.to_lowercase()),
+        None => false,
+    };
+
+    if !matches {
+        return Err(format!(
+            "{} {:?} doesn't match required {:?}, not flashing",
+            key, actual, required
+        )
+        .into());
+    }
+    Ok(())
 }
 
-fn bininfo(d: &HidDevice) {
-    let bininfo = hf2::bin_info(&d).expect("bin_info failed");
-    println!(
-        "{:?} {:?}kb",
-        bininfo,
-        bininfo.flash_num_pages * bininfo.flash_page_size / 1024
-    );
+fn bininfo(d: &Device, format: Format) -> Result<(), Error> {
+    let bininfo = hf2::bin_info(d)?;
+    //widened, a misbehaving bootloader's page count times page size can overflow u32
+    let flash_size_kb =
+        u64::from(bininfo.flash_num_pages) * u64::from(bininfo.flash_page_size) / 1024;
+
+    match format {
+        Format::Human => println!("{:?} {:?}kb", bininfo, flash_size_kb),
+        Format::Json => {
+            #[derive(Serialize)]
+            struct BinInfo<'a> {
+                #[serde(flatten)]
+                bininfo: &'a hf2::BinInfoResponse,
+                flash_size_kb: u64,
+            }
+
+            print_json(&BinInfo {
+                bininfo: &bininfo,
+                flash_size_kb,
+            })?
+        }
+    }
+    Ok(())
 }
 
-fn dmesg(d: &HidDevice) {
+fn dmesg(d: &Device, strict: bool) -> Result<(), Error> {
     // todo, test. not supported on my board
-    let dmesg = hf2::dmesg(&d).expect("dmesg failed");
+    let dmesg = if strict {
+        hf2::dmesg_strict(d)
+    } else {
+        hf2::dmesg(d)
+    }?;
     println!("{:?}", dmesg);
+    Ok(())
 }
 
-fn flash(file: PathBuf, address: u32, d: &HidDevice, skip_checksum: bool) {
-    let bininfo = hf2::bin_info(&d).expect("bin_info failed");
-    log::debug!("{:?}", bininfo);
+///Print the log as it grows, reprinting it from the start whenever the device's buffer wraps or resets.
+fn dmesg_follow(d: &Device, strict: bool, interval: Duration) -> Result<(), Error> {
+    let mut follower = hf2::DmesgFollower::default();
+
+    loop {
+        let dmesg = if strict {
+            hf2::dmesg_strict(d)
+        } else {
+            hf2::dmesg(d)
+        }?;
+
+        print!("{}", follower.update(dmesg.logs));
+        std::io::stdout()
+            .flush()
+            .map_err(|e| format!("couldn't write to stdout: {}", e))?;
+
+        std::thread::sleep(interval);
+    }
+}
+
+///A file to flash.
+struct Image {
+    ///(address, data) in address order
+    segments: Vec<(u32, Binary)>,
+    ///family a .uf2 says it's built for
+    family_id: Option<hf2::FamilyId>,
+    ///sha256 of the file as it was read, before parsing or padding
+    sha256: String,
+}
 
-    if bininfo.mode != hf2::BinInfoMode::Bootloader {
-        let _ = hf2::start_flash(&d).expect("start_flash failed");
+///address, or when it's missing for a raw binary, where apps start on the board at location if it's a known one. A .uf2 or .hex carries its own addresses.
+fn known_address(
+    api: &HidApi,
+    address: Option<u32>,
+    file: &Path,
+    location: &Location,
+) -> Option<u32> {
+    let extension = file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    if address.is_some() || matches!(extension.as_deref(), Some("uf2") | Some("hex")) {
+        return address;
     }
 
-    //shouldnt there be a chunking interator for this?
-    let mut f = File::open(file).unwrap();
-    let mut binary = Vec::new();
-    f.read_to_end(&mut binary).unwrap();
+    let address = app_address(api, location)?;
 
-    //pad zeros to page size
-    let padded_num_pages = (binary.len() as f64 / f64::from(bininfo.flash_page_size)).ceil() as u32;
-    let padded_size = padded_num_pages * bininfo.flash_page_size;
-    log::debug!(
-        "binary is {} bytes, padding to {} bytes",
-        binary.len(),
-        padded_size
+    status!(
+        "no --address, using 0x{:X} where apps start on this board",
+        address
     );
+    Some(address)
+}
 
-    for _i in 0..(padded_size as usize - binary.len()) {
-        binary.push(0x0);
-    }
+///Where apps start on the board at location, when it's a known one. Serial ports don't say which board they are.
+fn app_address(api: &HidApi, location: &Location) -> Option<u32> {
+    let path = match location {
+        Location::Hid(path) => path,
+        Location::Port(_) => return None,
+    };
+    let device_info = api
+        .device_list()
+        .find(|device_info| device_info.path() == path.as_c_str())?;
 
-    if skip_checksum {
-        for (page_index, page) in binary.chunks(bininfo.flash_page_size as usize).enumerate() {
-            let mut xmodem = CRCu16::crc16xmodem();
+    hf2::known_device(device_info.vendor_id(), device_info.product_id())?.app_address
+}
 
-            xmodem.digest(&page);
+///Read file as (address, data) segments to flash. A .uf2 or .hex carries its own addresses and may have gaps, anything else is a raw binary written at address.
+fn read_image(file: &Path, address: Option<u32>) -> Result<Image, Error> {
+    let contents = Binary::open(file).map_err(Error::file(file))?;
+    //of what's flashed, the file could change on disk before the manifest is written
+    let sha256 = manifest::sha256(&contents);
 
-            let target_address = address + bininfo.flash_page_size * page_index as u32;
-            let _ = hf2::write_flash_page(&d, target_address, page.to_vec())
-                .expect("write_flash_page failed");
-        }
-    } else {
-        // get checksums of existing pages
-        let top_address = address + padded_size as u32;
-        let max_pages = bininfo.max_message_size / 2 - 2;
-        let steps = max_pages * bininfo.flash_page_size;
-        let mut device_checksums = vec![];
-
-        for target_address in (address..top_address).step_by(steps as usize) {
-            let pages_left = (top_address - target_address) / bininfo.flash_page_size;
-
-            let num_pages = if pages_left < max_pages {
-                pages_left
-            } else {
-                max_pages
-            };
-            let chk =
-                hf2::checksum_pages(&d, target_address, num_pages).expect("checksum_pages failed");
-            device_checksums.extend_from_slice(&chk.checksums[..]);
-        }
-        log::debug!("checksums received {:04X?}", device_checksums);
-
-        // only write changed contents
-        for (page_index, page) in binary.chunks(bininfo.flash_page_size as usize).enumerate() {
-            let mut xmodem = CRCu16::crc16xmodem();
-
-            xmodem.digest(&page);
-
-            if xmodem.get_crc() != device_checksums[page_index] {
-                log::debug!(
-                    "ours {:04X?} != {:04X?} theirs, updating page {}",
-                    xmodem.get_crc(),
-                    device_checksums[page_index],
-                    page_index,
-                );
+    let extension = file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
 
-                let target_address = address + bininfo.flash_page_size * page_index as u32;
-                let _ = hf2::write_flash_page(&d, target_address, page.to_vec())
-                    .expect("write_flash_page failed");
-            } else {
-                log::debug!("not updating page {}", page_index,);
+    let (segments, family_id) = match extension.as_deref() {
+        Some("uf2") => {
+            let uf2 = hf2::parse_uf2(&contents)
+                .map_err(|e| format!("couldn't parse {:?}: {}", file, e))?;
+            match uf2.family_id {
+                Some(family_id) => status!("uf2 for {}", family_id),
+                None => status!("uf2 without a family id"),
             }
+            (uf2.segments, uf2.family_id)
+        }
+        Some("hex") => {
+            let segments = std::str::from_utf8(&contents)
+                .map_err(hf2::Error::from)
+                .and_then(hf2::parse_ihex)
+                .map_err(|e| format!("couldn't parse {:?}: {}", file, e))?;
+            (segments, None)
+        }
+        _ => {
+            let address = address.ok_or_else(|| {
+                "--address is required unless flashing a .uf2 or .hex, or a known board with a default app address".to_string()
+            })?;
+            return Ok(Image {
+                segments: vec![(address, contents)],
+                family_id: None,
+                sha256,
+            });
         }
+    };
+
+    if segments.is_empty() {
+        return Err(format!("{:?} has no data for main flash", file).into());
+    }
+    if address.is_some() {
+        eprintln!("warning: ignoring --address, the file carries its own addresses");
     }
+    for (address, data) in &segments {
+        status!("segment 0x{:08X}, {} bytes", address, data.len());
+    }
+
+    Ok(Image {
+        segments: segments
+            .into_iter()
+            .map(|(address, data)| (address, data.into()))
+            .collect(),
+        family_id,
+        sha256,
+    })
+}
+
+fn flash(
+    file: PathBuf,
+    address: Option<u32>,
+    d: &Device,
+    skip_checksum: bool,
+    verify_each: bool,
+    label: Option<&str>,
+    progress: &mut Progress,
+) -> Result<hf2::FlashStats, Error> {
+    let config = hf2::FlasherConfig {
+        verify_each,
+        ..Default::default()
+    };
+    let options = FlashOptions {
+        skip_checksum,
+        label: label.map(String::from),
+        ..Default::default()
+    };
+    //nothing to reopen with, the flasher never hands another device back
+    flash_image(
+        read_image(&file, address)?,
+        d,
+        config,
+        &options,
+        None,
+        progress,
+    )
+    .map(|(stats, _)| stats)
+}
 
-    println!("Success");
-    let _ = hf2::reset_into_app(&d).expect("reset_into_app failed");
+///How an image is flashed, beyond the Flasher's own config.
+#[derive(Default)]
+struct FlashOptions {
+    ///write every page rather than only those whose checksum differs
+    skip_checksum: bool,
+    ///flash a uf2 built for another family than the device reports
+    force: bool,
+    ///write segments that share a page in file order rather than refusing them
+    allow_overlap: bool,
+    ///progress as plain lines starting with [label] rather than a bar, for flashes running side by side
+    label: Option<String>,
 }
 
-fn verify(file: PathBuf, address: u32, d: &HidDevice) {
-    let bininfo = hf2::bin_info(&d).expect("bin_info failed");
+///Gets a flash a fresh handle to the same device when its current one goes stale.
+type Reopen<'a> = Box<dyn FnMut() -> Result<Device, hf2::Error> + 'a>;
+
+///Refuse segments that aren't page aligned, don't fit or share a page, and a uf2 built for another family than the device reports, before a single page is written. Sharing a page is let through with allow_overlap and a uf2 for another family with force. Whether every page has to be written regardless of its checksum.
+fn check_image(
+    bininfo: &hf2::BinInfoResponse,
+    image: &Image,
+    options: &FlashOptions,
+) -> Result<bool, Error> {
+    let mut skip_checksum = options.skip_checksum;
+    //overlap is checked last, every segment is known to be aligned and fit by then
+    match bininfo.check_segments(&image.segments) {
+        Err(e @ hf2::Error::OverlappingSegments { .. }) if options.allow_overlap => {
+            eprintln!("warning: {}, writing them in file order", e);
+            //a shared page checksummed before an earlier segment rewrites it could be wrongly skipped
+            skip_checksum = true;
+        }
+        result => result?,
+    }
 
-    if bininfo.mode != hf2::BinInfoMode::Bootloader {
-        let _ = hf2::start_flash(&d).expect("start_flash failed");
+    match bininfo.check_family(image.family_id) {
+        Err(e) if options.force => eprintln!("warning: {}, flashing anyway", e),
+        result => result?,
     }
+    Ok(skip_checksum)
+}
+
+///Flash every segment of image in turn with a Flasher made from config, with the stats totalled. Its events go out on progress as they happen and a bar follows the pages written. With reopen the flash carries on through the device briefly dropping off the bus, the device it carried on with is returned to use in place of d.
+fn flash_image(
+    image: Image,
+    d: &Device,
+    config: hf2::FlasherConfig,
+    options: &FlashOptions,
+    reopen: Option<Reopen<'_>>,
+    progress: &mut Progress,
+) -> Result<(hf2::FlashStats, Option<Device>), Error> {
+    let (events, received) = std::sync::mpsc::channel();
 
-    //shouldnt there be a chunking interator for this?
-    let mut f = File::open(file).unwrap();
-    let mut binary = Vec::new();
-    f.read_to_end(&mut binary).unwrap();
+    let (result, failed) = std::thread::scope(|scope| {
+        let forward = scope.spawn(|| {
+            let mut failed = false;
+            for event in received {
+                failed |= matches!(event, hf2::FlashEvent::Failed { .. });
+                progress.emit(event);
+            }
+            failed
+        });
+
+        //the flasher owns the only sender, forward is done once it's dropped
+        let result = flash_segments(image, d, config, options, reopen, events);
+        (result, forward.join().unwrap_or_default())
+    });
+
+    //the flasher only reports failures of the flash itself, not of opening it or the checks before
+    if let Err(e) = &result {
+        if !failed {
+            progress.emit(hf2::FlashEvent::Failed {
+                error: e.to_string(),
+            });
+        }
+    }
+    if let Err(Error::Device(hf2::Error::Unverified { mismatches })) = &result {
+        for mismatch in mismatches {
+            eprintln!(
+                "didn't commit at 0x{:X} (page {}): device {:04X} != file {:04X}",
+                mismatch.address, mismatch.page_index, mismatch.device_crc, mismatch.file_crc
+            );
+        }
+    }
+    result
+}
 
-    //pad zeros to page size
-    let padded_num_pages = (binary.len() as f64 / f64::from(bininfo.flash_page_size)).ceil() as u32;
-    let padded_size = padded_num_pages * bininfo.flash_page_size;
-    for _i in 0..(padded_size as usize - binary.len()) {
-        binary.push(0x0);
+fn flash_segments(
+    image: Image,
+    d: &Device,
+    config: hf2::FlasherConfig,
+    options: &FlashOptions,
+    reopen: Option<Reopen<'_>>,
+    events: std::sync::mpsc::Sender<hf2::FlashEvent>,
+) -> Result<(hf2::FlashStats, Option<Device>), Error> {
+    let mut flasher = hf2::Flasher::with_config(d, config)?.with_events(events);
+    if let Some(reopen) = reopen {
+        flasher = flasher.with_reopen(reopen);
     }
 
-    // get checksums of existing pages
-    let top_address = address + padded_size as u32;
-    let max_pages = bininfo.max_message_size / 2 - 2;
-    let steps = max_pages * bininfo.flash_page_size;
-    let mut device_checksums = vec![];
+    let bininfo = flasher.bininfo().clone();
+    let skip_checksum = check_image(&bininfo, &image, options)?;
+
+    let mut stats = hf2::FlashStats::default();
+    for (address, binary) in &image.segments {
+        let num_pages = bininfo.pages(binary, *address).len() as u32;
+        let mut bar = None;
+
+        stats +=
+            flasher.flash_with_progress(*address, binary, skip_checksum, |written, to_write| {
+                bar.get_or_insert_with(|| match &options.label {
+                    Some(label) => Bar::labelled(to_write, num_pages - to_write, label),
+                    None => Bar::new(to_write, num_pages - to_write),
+                })
+                .set(written)
+            })?;
+    }
+    Ok((stats, flasher.into_reopened()))
+}
 
-    for target_address in (address..top_address).step_by(steps as usize) {
-        let pages_left = (top_address - target_address) / bininfo.flash_page_size;
+///Print the address of every page flashing image would write and how many that is, without writing any.
+fn dry_run_image(image: Image, d: &Device, options: &FlashOptions) -> Result<(), Error> {
+    let flasher = hf2::Flasher::new(d)?;
+    let bininfo = flasher.bininfo();
+    let skip_checksum = check_image(bininfo, &image, options)?;
 
-        let num_pages = if pages_left < max_pages {
-            pages_left
+    let mut changed = 0;
+    let mut total = 0;
+
+    for (address, binary) in &image.segments {
+        let pages = if skip_checksum {
+            vec![true; bininfo.pages(binary, *address).len()]
         } else {
-            max_pages
+            flasher.changed_pages(*address, binary)?
         };
-        let chk =
-            hf2::checksum_pages(&d, target_address, num_pages).expect("checksum_pages failed");
-        device_checksums.extend_from_slice(&chk.checksums[..]);
+
+        for (page_index, _) in pages.iter().enumerate().filter(|(_, c)| **c) {
+            println!(
+                "would write page at 0x{:X}",
+                address + page_index as u32 * bininfo.flash_page_size
+            );
+            changed += 1;
+        }
+        total += pages.len();
+    }
+
+    println!("{} of {} pages differ, nothing written", changed, total);
+    Ok(())
+}
+
+fn verify(
+    file: PathBuf,
+    address: Option<u32>,
+    d: &Device,
+    crc32: bool,
+    words_per_request: Option<u32>,
+) -> Result<(), Error> {
+    for (address, binary) in read_image(&file, address)?.segments {
+        verify_image(&binary, address, d, crc32, words_per_request)?;
+    }
+    Ok(())
+}
+
+fn verify_image(
+    binary: &[u8],
+    address: u32,
+    d: &Device,
+    crc32: bool,
+    words_per_request: Option<u32>,
+) -> Result<(), Error> {
+    let mut flasher = hf2::Flasher::new(d)?;
+    let bininfo = flasher.bininfo();
+
+    let num_pages = bininfo.pages(binary, address).len() as u32;
+
+    if crc32 {
+        let words_per_request =
+            clamp_words_per_request(words_per_request, hf2::max_words_per_read(bininfo)?);
+
+        //compare the whole padded pages, same as what was flashed
+        let padded: Vec<u8> = bininfo
+            .pages(binary, address)
+            .flat_map(|(_, page)| page.into_owned())
+            .collect();
+
+        match verify_crc32(&padded, address, d, words_per_request) {
+            Err(Error::Device(e)) if e.is_unsupported() => {
+                eprintln!(
+                    "warning: device doesn't support READ_WORDS, falling back to per page crc16"
+                )
+            }
+            result => return result,
+        }
+    }
+
+    let mismatches = flasher.verify(address, binary)?;
+
+    for mismatch in &mismatches {
+        println!(
+            "mismatch at 0x{:X} (page {}): device {:04X} != file {:04X}",
+            mismatch.address, mismatch.page_index, mismatch.device_crc, mismatch.file_crc
+        );
+    }
+
+    if !mismatches.is_empty() {
+        return Err(format!("{} of {} pages differ", mismatches.len(), num_pages).into());
+    }
+    status!("Success");
+    Ok(())
+}
+
+///Read the image region back and compare a CRC32 of it against the file, much less likely to collide than per page CRC16s.
+fn verify_crc32(
+    binary: &[u8],
+    address: u32,
+    d: &Device,
+    words_per_request: Option<u32>,
+) -> Result<(), Error> {
+    let ours = hf2::crc32(binary);
+    let theirs = hf2::memory_crc32(d, address, binary.len() as u32, words_per_request)?;
+
+    if ours != theirs {
+        return Err(format!("crc32 differs: file {:08X} != device {:08X}", ours, theirs).into());
+    }
+
+    status!("Success, crc32 {:08X}", ours);
+    Ok(())
+}
+
+///Print a CRC32 of each segment of the image next to one of the same number of bytes read back from where it would be flashed, a coarse check to eyeball before and after flashing.
+fn image_crc(
+    file: PathBuf,
+    address: Option<u32>,
+    d: &Device,
+    words_per_request: Option<u32>,
+) -> Result<(), Error> {
+    let max = hf2::max_words_per_read(&hf2::bin_info(d)?)?;
+    let words_per_request = clamp_words_per_request(words_per_request, max);
+
+    let segments = read_image(&file, address)?.segments;
+    let mut differ = 0;
+
+    for (address, binary) in &segments {
+        let ours = hf2::crc32(binary);
+        let theirs = hf2::memory_crc32(d, *address, binary.len() as u32, words_per_request)?;
+
+        println!(
+            "0x{:08X}, {} bytes: file {:08X} device {:08X}{}",
+            address,
+            binary.len(),
+            ours,
+            theirs,
+            if ours == theirs { "" } else { " differ" }
+        );
+        if ours != theirs {
+            differ += 1;
+        }
     }
 
-    let mut binary_checksums = vec![];
+    if differ > 0 {
+        return Err(format!("{} of {} segments differ", differ, segments.len()).into());
+    }
+    Ok(())
+}
+
+///Checksum the image's pages like verify, then read back only the ones that differ with READ_WORDS and print the first limit bytes that differ as address, file, device.
+fn compare(
+    file: PathBuf,
+    address: Option<u32>,
+    limit: usize,
+    d: &Device,
+    words_per_request: Option<u32>,
+) -> Result<(), Error> {
+    let mut pages = 0;
+    let mut bytes = 0;
 
-    //collect and sums so we can view all mismatches, not just first
-    for page in binary.chunks(bininfo.flash_page_size as usize) {
-        let mut xmodem = CRCu16::crc16xmodem();
-        xmodem.digest(&page);
+    for (address, binary) in read_image(&file, address)?.segments {
+        let mut flasher = hf2::Flasher::new(d)?;
+        let bininfo = flasher.bininfo().clone();
+        let words_per_request =
+            clamp_words_per_request(words_per_request, hf2::max_words_per_read(&bininfo)?);
+
+        let padded: Vec<_> = bininfo.pages(&binary, address).collect();
+
+        for mismatch in flasher.verify(address, &binary)? {
+            let (_, page) = &padded[mismatch.page_index as usize];
+            let readback =
+                hf2::read_memory(d, mismatch.address, page.len() as u32, words_per_request)?;
+            pages += 1;
+
+            for (offset, (file, device)) in page.iter().zip(&readback).enumerate() {
+                if file == device {
+                    continue;
+                }
+                if bytes < limit {
+                    println!(
+                        "0x{:08X}: file {:02X} device {:02X}",
+                        mismatch.address as usize + offset,
+                        file,
+                        device
+                    );
+                }
+                bytes += 1;
+            }
+        }
+    }
+
+    if pages > 0 {
+        if bytes > limit {
+            println!("... {} more", bytes - limit);
+        }
+        return Err(format!("{} bytes differ in {} pages", bytes, pages).into());
+    }
+    status!("Success");
+    Ok(())
+}
 
-        binary_checksums.push(xmodem.get_crc());
+///Print the 32 bit word at a word aligned address.
+fn peek(address: u32, d: &Device) -> Result<(), Error> {
+    if !address.is_multiple_of(4) {
+        return Err(format!("0x{:08X} isn't word aligned", address).into());
     }
 
-    //only check as many as our binary has
-    assert_eq!(
-        &binary_checksums[..binary_checksums.len()],
-        &device_checksums[..binary_checksums.len()]
+    let word = hf2::read_word(d, address)?;
+    println!("0x{:08X}: 0x{:08X} ({})", address, word, word);
+    Ok(())
+}
+
+///Write value as the 32 bit word at a word aligned address, with verify reading it back.
+fn poke(address: u32, value: u32, verify: bool, d: &Device) -> Result<(), Error> {
+    if !address.is_multiple_of(4) {
+        return Err(format!("0x{:08X} isn't word aligned", address).into());
+    }
+
+    hf2::write_word(d, address, value)?;
+
+    if verify {
+        let word = hf2::read_word(d, address)?;
+        if word != value {
+            return Err(format!(
+                "0x{:08X} reads back 0x{:08X} instead of 0x{:08X}",
+                address, word, value
+            )
+            .into());
+        }
+    }
+
+    println!("0x{:08X}: 0x{:08X} ({})", address, value, value);
+    Ok(())
+}
+
+///Print the CRC of each of num_pages pages from address as the device computes it, in as few requests as max_message_size allows.
+fn checksum(address: u32, num_pages: u32, d: &Device) -> Result<(), Error> {
+    let page_size = hf2::bin_info(d)?.flash_page_size;
+
+    for (page_index, checksum) in hf2::checksum_region_iter(d, address, num_pages)?.enumerate() {
+        println!(
+            "0x{:08X}: {:04X}",
+            address + page_index as u32 * page_size,
+            checksum?
+        );
+    }
+    Ok(())
+}
+
+///Print which pages hold data, compared against an erased page, and the extent they cover.
+fn occupied(address: u32, num_pages: Option<u32>, d: &Device) -> Result<(), Error> {
+    let bininfo = hf2::bin_info(d)?;
+    let page_size = bininfo.flash_page_size;
+
+    let num_pages =
+        num_pages.unwrap_or_else(|| bininfo.flash_num_pages.saturating_sub(address / page_size));
+
+    let dirty = hf2::dirty_pages(d, address, num_pages)?;
+
+    //collapse consecutive pages into ranges
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for &index in &dirty {
+        match ranges.last_mut() {
+            Some((_, end)) if *end == index => *end += 1,
+            _ => ranges.push((index, index + 1)),
+        }
+    }
+
+    for (start, end) in &ranges {
+        println!(
+            "0x{:08X}..0x{:08X} pages {}..{}",
+            address + start * page_size,
+            address + end * page_size,
+            start,
+            end
+        );
+    }
+
+    match (dirty.first(), dirty.last()) {
+        (Some(first), Some(last)) => println!(
+            "{} of {} pages contain data, occupied extent 0x{:08X}..0x{:08X}",
+            dirty.len(),
+            num_pages,
+            address + first * page_size,
+            address + (last + 1) * page_size
+        ),
+        _ => println!("all {} pages are erased", num_pages),
+    }
+    Ok(())
+}
+
+///Read the whole flash, or length bytes from address, and write it to file in format.
+fn dump(
+    file: PathBuf,
+    address: Option<u32>,
+    length: Option<u32>,
+    format: &str,
+    d: &Device,
+    words_per_request: Option<u32>,
+) -> Result<(), Error> {
+    let (address, memory) = match (address, length) {
+        (Some(address), Some(length)) => {
+            let bininfo = hf2::bin_info(d)?;
+            let max = hf2::max_words_per_read(&bininfo)?;
+            let words_per_request = clamp_words_per_request(words_per_request, max);
+
+            let memory = hf2::read_memory(d, address, length, words_per_request)?;
+            (address, memory)
+        }
+        (None, None) => (0, hf2::read_flash(d)?),
+        _ => {
+            return Err(
+                "dump needs both --address and --length, or neither for the whole flash"
+                    .to_string()
+                    .into(),
+            )
+        }
+    };
+
+    let length = memory.len();
+    let contents = match format {
+        "srec" => hf2::to_srec(address, &memory).into_bytes(),
+        _ => memory,
+    };
+
+    std::fs::write(&file, contents).map_err(Error::file(&file))?;
+    status!(
+        "dumped {} bytes from 0x{:08X} to {:?}",
+        length,
+        address,
+        file
     );
-    println!("Success");
+    Ok(())
+}
+
+///Clamp --words-per-request to between 1 and the most a single request can carry, warning when it had to.
+fn clamp_words_per_request(requested: Option<u32>, max: u32) -> Option<u32> {
+    requested.map(|words| {
+        let clamped = words.clamp(1, max);
+        if clamped != words {
+            eprintln!(
+                "warning: --words-per-request {} outside of 1..={} allowed by max_message_size, using {}",
+                words, max, clamped
+            );
+        }
+        clamped
+    })
+}
+
+///Wrap an opened device with the framing options from the command line.
+fn wrap(
+    d: impl Into<Transport>,
+    report_size: usize,
+    timeout: Option<u64>,
+    capture: Option<&File>,
+) -> Result<Device, Error> {
+    let d = with_report_size(d, report_size, timeout)?;
+
+    Ok(match capture {
+        Some(file) => {
+            let mut file = file
+                .try_clone()
+                .map_err(|e| format!("couldn't clone capture file: {}", e))?;
+
+            d.on_raw(move |direction, report| {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                let direction = match direction {
+                    hf2::Direction::Write => "tx",
+                    hf2::Direction::Read => "rx",
+                };
+
+                //best effort like progress, a full disk shouldn't fail the flash
+                if let Err(e) = writeln!(file, "{:.6} {} {:02X?}", timestamp, direction, report) {
+                    log::debug!("capture write failed {:?}", e);
+                }
+            })
+        }
+        None => d,
+    })
+}
+
+///Device::with_report_size, explaining the allowed range when --report-size is outside of it, and with --timeout in milliseconds if given.
+fn with_report_size(
+    d: impl Into<Transport>,
+    report_size: usize,
+    timeout: Option<u64>,
+) -> Result<Device, Error> {
+    let d = Device::with_report_size(d.into(), report_size).map_err(|_| {
+        format!(
+            "--report-size must be between {} and {}",
+            hf2::MIN_REPORT_SIZE,
+            hf2::REPORT_SIZE
+        )
+    })?;
+
+    Ok(match timeout {
+        Some(timeout) => d.with_timeout(Duration::from_millis(timeout)),
+        None => d,
+    })
+}
+
+///Open the device matching vid and pid, or the first known bootloader when they aren't given, only considering ones with serial if given. Also returns its path so the same device can be opened again later.
+fn open_device(
+    api: &HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+) -> Result<(HidDevice, CString), Error> {
+    match hf2::open_first(api, hf2::matching_devices(api, vid, pid, serial)) {
+        Ok((d, device_info)) => Ok((d, device_info.path().to_owned())),
+        Err(hf2::Error::PermissionDenied) => {
+            let device_info = hf2::matching_devices(api, vid, pid, serial)
+                .next()
+                .ok_or(Error::Device(hf2::Error::PermissionDenied))?;
+
+            Err(Error::PermissionDenied {
+                vid: device_info.vendor_id(),
+                pid: device_info.product_id(),
+            })
+        }
+        Err(hf2::Error::NotFound) => Err(Error::NotFound {
+            searched: match (vid, pid) {
+                (Some(v), Some(p)) => vec![(v, p)],
+                _ => known(),
+            },
+            serial: serial.map(String::from),
+        }),
+        Err(e) => Err(Error::Device(e)),
+    }
+}
+
+///Open the device at location again once its handle went stale, by vid/pid and serial like it was first found rather than by its hid path, which can change when it comes back on the bus. A port is opened by name again.
+fn reopen_stale(
+    api: &mut HidApi,
+    location: &Location,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+) -> Result<(Transport, Location), Error> {
+    match location {
+        Location::Hid(_) => {
+            api.refresh_devices()?;
+            let (d, path) = open_device(api, vid, pid, serial)?;
+            Ok((d.into(), Location::Hid(path)))
+        }
+        Location::Port(port) => Ok((
+            hf2::SerialTransport::open(port)?.into(),
+            Location::Port(port.clone()),
+        )),
+    }
+}
+
+///If d is running its app, reset it into the bootloader and reopen it once it comes back answering in bootloader mode, None when it already is in the bootloader.
+fn enter_bootloader(
+    api: &mut HidApi,
+    d: &Device,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    timeout: Duration,
+) -> Result<Option<(HidDevice, CString)>, Error> {
+    if hf2::bin_info(d)?.mode == hf2::BinInfoMode::Bootloader {
+        return Ok(None);
+    }
+
+    status!("resetting into bootloader");
+    let mode = hf2::BinInfoMode::Bootloader;
+    match reset_and_wait(api, d, vid, pid, serial, mode, timeout) {
+        Ok(reopened) => Ok(Some(reopened)),
+        Err(Error::Device(hf2::Error::Timeout)) => Err(hf2::Error::NoBootloader { timeout }.into()),
+        Err(e) => Err(e),
+    }
+}
+
+///Reset d into mode and reopen it once it answers bininfo in that mode, Error::Timeout if it doesn't within timeout. It's matched by the serial it had before the reset if any, otherwise by vid/pid or the known bootloaders like when it was first opened.
+fn reset_and_wait(
+    api: &mut HidApi,
+    d: &Device,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    mode: hf2::BinInfoMode,
+    timeout: Duration,
+) -> Result<(HidDevice, CString), Error> {
+    let serial = match serial {
+        Some(serial) => Some(serial.to_string()),
+        None => d.serial_number().filter(|s| !s.is_empty()),
+    };
+
+    //usually no response, the device may well be gone before the write returns
+    let reset = match mode {
+        hf2::BinInfoMode::Bootloader => hf2::reset_into_bootloader(d),
+        hf2::BinInfoMode::User => hf2::reset_into_app(d),
+    };
+    if let Err(e) = reset {
+        log::debug!("reset into {:?} {}", mode, e);
+    }
+
+    Ok(hf2::wait_for_mode(
+        api,
+        vid,
+        pid,
+        serial.as_deref(),
+        mode,
+        timeout,
+    )?)
+}
+
+///A number of seconds given to --wait.
+fn seconds(wait: f64) -> Result<Duration, Error> {
+    Ok(Duration::try_from_secs_f64(wait)
+        .map_err(|_| format!("--wait {} isn't a number of seconds", wait))?)
+}
+
+///How to grant ourselves access to vid/pid on this platform.
+fn permission_hint(vid: u16, pid: u16) -> String {
+    if cfg!(target_os = "linux") {
+        format!(
+            "add udev rules for the device, for example to /etc/udev/rules.d/99-hf2.rules\n\
+             \x20 SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{vid:04x}\", ATTRS{{idProduct}}==\"{pid:04x}\", MODE=\"0666\"\n\
+             \x20 KERNEL==\"hidraw*\", ATTRS{{idVendor}}==\"{vid:04x}\", ATTRS{{idProduct}}==\"{pid:04x}\", MODE=\"0666\"\n\
+             then run sudo udevadm control --reload-rules && sudo udevadm trigger and replug",
+            vid = vid,
+            pid = pid
+        )
+    } else if cfg!(target_os = "macos") {
+        "allow Input Monitoring for your terminal in System Preferences, Security & Privacy".into()
+    } else {
+        "make sure no other program has the device open".into()
+    }
+}
+
+///Print every connected device we'd consider opening, without opening any.
+fn list(
+    api: &HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    format: Format,
+) -> Result<(), Error> {
+    #[derive(Serialize)]
+    struct Listed<'a> {
+        vendor_id: u16,
+        product_id: u16,
+        manufacturer: Option<&'a str>,
+        product: Option<&'a str>,
+        serial: Option<&'a str>,
+        path: String,
+    }
+
+    let listed: Vec<Listed> = hf2::matching_devices(api, vid, pid, serial)
+        .map(|device_info| Listed {
+            vendor_id: device_info.vendor_id(),
+            product_id: device_info.product_id(),
+            manufacturer: device_info.manufacturer_string(),
+            product: device_info.product_string(),
+            serial: device_info.serial_number(),
+            path: device_info.path().to_string_lossy().into_owned(),
+        })
+        .collect();
+
+    if format == Format::Json {
+        return print_json(&listed);
+    }
+
+    for device in &listed {
+        println!(
+            "{:04x}:{:04x} {:?} {:?} serial {:?}",
+            device.vendor_id,
+            device.product_id,
+            device.manufacturer.unwrap_or_default(),
+            device.product.unwrap_or_default(),
+            device.serial.unwrap_or_default()
+        );
+    }
+
+    if listed.is_empty() {
+        println!("no matching devices found");
+    }
+    Ok(())
+}
+
+///Every known vid/pid pair, sorted, for telling the user what we searched.
+fn known() -> Vec<(u16, u16)> {
+    let mut known: Vec<(u16, u16)> = hf2::known_devices()
+        .iter()
+        .map(|known| (known.vid, known.pid))
+        .collect();
+    known.sort_unstable();
+    known
+}
+
+fn compare_devices(
+    api: &HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serials: &[String],
+    address: u32,
+    length: u32,
+) -> Result<(), Error> {
+    if serials.len() != 2 {
+        return Err("compare-devices needs exactly two --serial arguments"
+            .to_string()
+            .into());
+    }
+
+    let mut checksums = vec![];
+    let mut flash_page_size = 0;
+
+    for serial in serials {
+        let (d, _) = open_device(api, vid, pid, Some(serial))?;
+
+        let bininfo = hf2::bin_info(&d)?;
+        if bininfo.mode != hf2::BinInfoMode::Bootloader {
+            hf2::start_flash_if_supported(&d)?;
+        }
+
+        //both boards need the same page layout for a page by page compare to mean anything
+        if flash_page_size != 0 && flash_page_size != bininfo.flash_page_size {
+            return Err("devices report different flash page sizes"
+                .to_string()
+                .into());
+        }
+        flash_page_size = bininfo.flash_page_size;
+
+        let num_pages = length.div_ceil(flash_page_size);
+        let chk = hf2::checksum_region(&d, address, num_pages)?;
+        log::debug!("{} checksums {:04X?}", serial, chk);
+
+        checksums.push(chk);
+    }
+
+    let mut mismatches = 0;
+    for (page_index, (a, b)) in checksums[0].iter().zip(checksums[1].iter()).enumerate() {
+        if a != b {
+            println!(
+                "mismatch at 0x{:08X} (page {}): {} {:04X} != {} {:04X}",
+                address + flash_page_size * page_index as u32,
+                page_index,
+                serials[0],
+                a,
+                serials[1],
+                b
+            );
+            mismatches += 1;
+        }
+    }
+
+    if mismatches > 0 {
+        return Err(format!("{} of {} pages differ", mismatches, checksums[0].len()).into());
+    }
+    status!("Success");
+    Ok(())
 }
 
 fn parse_hex_32(input: &str) -> Result<u32, std::num::ParseIntError> {
-    if input.starts_with("0x") {
-        u32::from_str_radix(&input[2..], 16)
+    if let Some(hex) = input.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
     } else {
         input.parse::<u32>()
     }
 }
 fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
-    if input.starts_with("0x") {
-        u16::from_str_radix(&input[2..], 16)
+    if let Some(hex) = input.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
     } else {
         input.parse::<u16>()
     }
@@ -243,9 +1518,17 @@ fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
 #[derive(StructOpt, Debug, PartialEq)]
 pub enum Cmd {
     ///Reset the device into user-space app.
-    resetIntoApp,
+    resetIntoApp {
+        /// seconds to wait for the app to answer bininfo afterwards, only apps with an hf2 interface ever do
+        #[structopt(long = "wait")]
+        wait: Option<f64>,
+    },
     ///Reset the device into bootloader, usually for flashing
-    resetIntoBootloader,
+    resetIntoBootloader {
+        /// seconds to wait for the bootloader to come back answering bininfo, instead of returning right after the reset
+        #[structopt(long = "wait")]
+        wait: Option<f64>,
+    },
 
     /// Various device information. The result is a character array. See INFO_UF2.TXT in UF2 format for details.
     info,
@@ -253,25 +1536,250 @@ pub enum Cmd {
     /// This command states the current mode of the device
     bininfo,
 
+    /// print the flash size, page size and, for known boards, where the bootloader ends and apps start
+    layout,
+
     ///Return internal log buffer if any. The result is a character array.
-    dmesg,
+    dmesg {
+        /// fail on invalid utf8 instead of replacing it
+        #[structopt(long)]
+        strict: bool,
+
+        /// keep polling and print only what was appended, until interrupted
+        #[structopt(short = "f", long = "follow")]
+        follow: bool,
+
+        /// milliseconds between polls with --follow
+        #[structopt(
+            short = "i",
+            name = "interval",
+            long = "interval",
+            default_value = "500"
+        )]
+        interval: u64,
+    },
 
     /// flash
     flash {
+        /// image to flash, - reads a raw binary from stdin
         #[structopt(short = "f", name = "file", long = "file")]
         file: PathBuf,
+        /// where to write the file, not needed for a .uf2 or .hex which carry their own addresses
         #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
-        address: u32,
+        address: Option<u32>,
+        /// don't read the device's checksums to compare against, which leaves every page to be written
+        #[structopt(short, long)]
+        skip_checksum: bool,
+        /// write every page whether it differs or not, then checksum them all again as with --verify-after
+        #[structopt(long, conflicts_with = "skip-checksum")]
+        force_all: bool,
+        /// compare checksums and print which pages would be written, without writing any
+        #[structopt(long, conflicts_with_all = &["skip-checksum", "force-all"])]
+        dry_run: bool,
+        /// close and reopen the device right before flashing, after the commands checking it, works around some driver quirks
+        #[structopt(long)]
+        reopen: bool,
+        /// if the board is running its app, reset it into the bootloader and wait for it to come back before flashing
+        #[structopt(long)]
+        auto_bootloader: bool,
+        /// milliseconds to wait for the board to come back with --auto-bootloader
+        #[structopt(long, default_value = "10000")]
+        bootloader_timeout: u64,
+        /// only flash if the Model reported by info contains this, case insensitive
+        #[structopt(long)]
+        require_model: Option<String>,
+        /// only flash if the Board-ID reported by info contains this, case insensitive
+        #[structopt(long)]
+        require_board_id: Option<String>,
+        /// require the whole Model/Board-ID to match exactly instead
+        #[structopt(long)]
+        exact: bool,
+        /// checksum every page right after writing it and rewrite it on mismatch, slower
+        #[structopt(long)]
+        verify_each: bool,
+        /// once everything is written, checksum the written pages again and fail if any of them didn't commit
+        #[structopt(long)]
+        verify_after: bool,
+        /// write as many consecutive pages per message as the device's max message size allows, falling back to a page at a time if it rejects that
+        #[structopt(long)]
+        batch: bool,
+        /// flash a .uf2 even when it's built for a different family than the device reports
+        #[structopt(long)]
+        force: bool,
+        /// sanity check the image's vector table before flashing, only cortex-m for now
+        #[structopt(long, possible_values = &["cortex-m"])]
+        check_vectors: Option<String>,
+        /// lowest address the initial stack pointer may point above
+        #[structopt(long, parse(try_from_str = parse_hex_32), default_value = "0x20000000")]
+        ram_start: u32,
+        /// highest address the initial stack pointer may point to
+        #[structopt(long, parse(try_from_str = parse_hex_32), default_value = "0x40000000")]
+        ram_end: u32,
+        /// abort instead of warning when --check-vectors finds a problem
+        #[structopt(long, requires = "check-vectors")]
+        strict: bool,
+        /// after flashing, verify and write a json record of the device, image and result to this path
+        #[structopt(long)]
+        manifest: Option<PathBuf>,
+        /// append the manifest as a single json line instead of overwriting the file
+        #[structopt(long, requires = "manifest")]
+        manifest_append: bool,
+        /// after flashing and resetting a board, wait for it to be unplugged and flash the next matching one plugged in, until interrupted
+        #[structopt(long, conflicts_with = "dry-run")]
+        repeat: bool,
+        /// flash a .uf2 or .hex whose segments share a page anyway, writing them in file order so the last one wins the shared pages
+        #[structopt(long)]
+        allow_overlap: bool,
+        /// leave the device in the bootloader once it's flashed instead of resetting it into the app
+        #[structopt(long)]
+        no_reset: bool,
+    },
+
+    /// flash the same image to every matching device in parallel and summarize per serial
+    flashAll {
+        #[structopt(short = "f", name = "file", long = "file")]
+        file: PathBuf,
+        /// where to write the file, not needed for a .uf2 or .hex which carry their own addresses
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
         #[structopt(short, long)]
         skip_checksum: bool,
+        /// most devices to flash at once, defaults to all of them
+        #[structopt(short = "j", name = "jobs", long = "jobs")]
+        jobs: Option<usize>,
     },
 
     /// verify
     verify {
         #[structopt(short = "f", name = "file", long = "file")]
         file: PathBuf,
+        /// where to write the file, not needed for a .uf2 or .hex which carry their own addresses
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
+        /// read the image back and compare a single CRC32 instead of per page CRC16s
+        #[structopt(long)]
+        crc32: bool,
+    },
+
+    /// read back the pages whose checksum differs from the file and print the bytes that differ
+    compare {
+        #[structopt(short = "f", name = "file", long = "file")]
+        file: PathBuf,
+        /// where to write the file, not needed for a .uf2 or .hex which carry their own addresses
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
+        /// most differing bytes to print, every differing page is still counted
+        #[structopt(short = "n", long, default_value = "32")]
+        limit: usize,
+    },
+
+    /// print a crc32 of the file next to one of the same bytes read back from the device
+    imageCrc {
+        #[structopt(short = "f", name = "file", long = "file")]
+        file: PathBuf,
+        /// where the file is flashed, not needed for a .uf2 or .hex which carry their own addresses
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
+    },
+
+    /// read and decode the user/option bytes, protection and watchdog state, for the connected family
+    options {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
+        #[structopt(short = "w", name = "words", long = "words", parse(try_from_str = parse_hex_32))]
+        words: Option<u32>,
+    },
+
+    /// list the pages that differ from an erased page, ie hold data, without needing an image
+    occupied {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32), default_value = "0")]
+        address: u32,
+        /// number of pages to check, defaults to the rest of the flash
+        #[structopt(short = "n", name = "pages", long = "pages")]
+        pages: Option<u32>,
+    },
+
+    /// print the crc16 the device computes for each page of a range, same as flash and verify compare against
+    checksum {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// number of pages to checksum
+        #[structopt(short = "n", name = "pages", long = "pages", default_value = "1")]
+        pages: u32,
+    },
+
+    /// overwrite a page aligned range with zeros, a logical erase by writing rather than a hardware sector erase
+    erase {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// bytes to erase, rounded up to whole pages
+        #[structopt(short = "l", name = "length", long = "length", parse(try_from_str = parse_hex_32))]
+        length: u32,
+    },
+
+    /// read and print the 32 bit word at a word aligned address
+    peek {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+    },
+
+    /// write a 32 bit word at a word aligned address
+    poke {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        #[structopt(long = "value", parse(try_from_str = parse_hex_32))]
+        value: u32,
+        /// read the word back and fail if it doesn't hold value
+        #[structopt(long)]
+        verify: bool,
+    },
+
+    /// read the whole flash, or length bytes from address, into a file
+    #[structopt(visible_alias = "read")]
+    dump {
+        #[structopt(short = "f", name = "file", long = "file")]
+        file: PathBuf,
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
+        #[structopt(short = "l", name = "length", long = "length", parse(try_from_str = parse_hex_32))]
+        length: Option<u32>,
+        /// bin for a raw image, srec for motorola s-records with 32 bit addresses
+        #[structopt(long = "file-format", default_value = "bin", possible_values = &["bin", "srec"])]
+        format: String,
+    },
+
+    /// run an ordered list of operations from a toml script against one device
+    batch {
+        #[structopt(short = "s", name = "script", long = "script")]
+        script: PathBuf,
+    },
+
+    /// list connected devices matching --vid/--pid, or every known bootloader, without opening them
+    list,
+
+    /// check enumeration, permissions, bininfo and latency and suggest fixes for what fails
+    doctor,
+
+    /// poll bininfo and print a timestamped line whenever the mode changes, until interrupted
+    watchMode {
+        /// milliseconds between polls
+        #[structopt(
+            short = "i",
+            name = "interval",
+            long = "interval",
+            default_value = "250"
+        )]
+        interval: u64,
+    },
+
+    /// checksum the same region on two devices and report pages that differ
+    compareDevices {
+        #[structopt(short = "s", name = "serial", long = "serial", number_of_values = 1)]
+        serial: Vec<String>,
         #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
         address: u32,
+        #[structopt(short = "l", name = "length", long = "length", parse(try_from_str = parse_hex_32))]
+        length: u32,
     },
 }
 
@@ -285,4 +1793,74 @@ struct Opt {
     pid: Option<u16>,
     #[structopt(short = "v", name = "vid", long = "vid", parse(try_from_str = parse_hex_16))]
     vid: Option<u16>,
+
+    /// only consider the device with this serial number, see list
+    #[structopt(name = "serial", long = "serial")]
+    serial: Option<String>,
+
+    /// words per read_words/write_words request, defaults to the most max_message_size allows
+    #[structopt(name = "words-per-request", long = "words-per-request")]
+    words_per_request: Option<u32>,
+
+    /// hid report size of the device, some use fewer than the 64 bytes the hf2 spec assumes
+    #[structopt(name = "report-size", long = "report-size", default_value = "64")]
+    report_size: usize,
+
+    /// milliseconds to wait for each packet of a response before giving up, by default each read waits a second and a silent device is retried a few times
+    #[structopt(name = "timeout", long = "timeout")]
+    timeout: Option<u64>,
+
+    /// seconds to wait for a matching device to enumerate before giving up, instead of failing right away
+    #[structopt(long = "wait")]
+    wait: Option<f64>,
+
+    /// talk to a bootloader speaking hf2 over this serial port, COM3 or /dev/ttyACM0 say, instead of hid
+    #[structopt(long = "port")]
+    port: Option<String>,
+
+    /// write every raw hid report sent and received to this file, one per line with a timestamp and direction
+    #[structopt(name = "capture", long = "capture")]
+    capture: Option<PathBuf>,
+
+    /// write newline delimited json flash progress events to this already open file descriptor
+    #[structopt(name = "progress-fd", long = "progress-fd")]
+    progress_fd: Option<i32>,
+
+    /// log more, debug and then trace when given twice
+    #[structopt(long = "verbose", parse(from_occurrences))]
+    verbose: u8,
+
+    /// only print errors, warnings and what the command read, no status lines or progress bar
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// how info, bininfo, layout and list print their results
+    #[structopt(long = "format", default_value = "human", possible_values = &["human", "json"])]
+    format: Format,
+}
+
+///Output format for commands that report what they read.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Format {
+    Human,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Format::Human),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+///Print value as a single line of json.
+fn print_json<T: Serialize>(value: &T) -> Result<(), Error> {
+    let json = serde_json::to_string(value).map_err(|e| format!("couldn't serialize: {}", e))?;
+    println!("{}", json);
+    Ok(())
 }
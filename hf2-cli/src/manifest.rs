@@ -0,0 +1,87 @@
+use crate::error::Error;
+use crate::transport::Device;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///Auditable record of a single flashed unit.
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    ///seconds since the unix epoch when the flash finished
+    pub timestamp: u64,
+    pub serial: Option<String>,
+    pub model: Option<String>,
+    pub board_id: Option<String>,
+    pub image: PathBuf,
+    ///sha256 of the image file as it was read for flashing, before parsing or padding
+    pub sha256: String,
+    ///lowest address the image wrote to, where a bin went or a hex or uf2's first segment
+    pub address: u32,
+    pub stats: hf2::FlashStats,
+    ///whether the flash checksummed the written pages again and they all matched
+    pub verified: bool,
+}
+
+impl Manifest {
+    pub fn new(
+        d: &Device,
+        image: PathBuf,
+        sha256: String,
+        address: u32,
+        stats: hf2::FlashStats,
+        verified: bool,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+
+        let serial = d.serial_number();
+
+        //not every bootloader answers info, the record is still worth having without it
+        let info = hf2::info(d).ok();
+
+        Manifest {
+            timestamp,
+            serial,
+            model: info.as_ref().and_then(|info| info.model.clone()),
+            board_id: info.and_then(|info| info.board_id),
+            image,
+            sha256,
+            address,
+            stats,
+            verified,
+        }
+    }
+
+    ///Overwrite path with this manifest as pretty json, or append it as a single json line.
+    pub fn write(&self, path: &Path, append: bool) -> Result<(), Error> {
+        let json = if append {
+            serde_json::to_string(self)
+        } else {
+            serde_json::to_string_pretty(self)
+        }
+        .map_err(|e| format!("couldn't serialize manifest: {}", e))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .map_err(Error::file(path))?;
+
+        writeln!(file, "{}", json).map_err(Error::file(path))
+    }
+}
+
+///Lowercase hex sha256 of image.
+pub fn sha256(image: &[u8]) -> String {
+    Sha256::digest(image)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
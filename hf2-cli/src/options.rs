@@ -0,0 +1,164 @@
+use crate::error::Error;
+use crate::transport::Device;
+use hf2::FamilyId;
+
+///Where a family keeps its user/option bytes and how to make sense of them.
+struct OptionRegion {
+    address: u32,
+    num_words: u32,
+    decode: fn(&[u32]) -> Vec<(&'static str, String)>,
+}
+
+fn region(family_id: FamilyId) -> Option<OptionRegion> {
+    match family_id {
+        FamilyId::STM32F103 => Some(OptionRegion {
+            address: 0x1FFF_F800,
+            num_words: 4,
+            decode: stm32f103,
+        }),
+        FamilyId::STM32F401 => Some(OptionRegion {
+            address: 0x1FFF_C000,
+            num_words: 1,
+            decode: stm32f401,
+        }),
+        FamilyId::ATSAMD21 => Some(OptionRegion {
+            address: 0x0080_4000,
+            num_words: 2,
+            decode: atsamd21,
+        }),
+        FamilyId::ATSAMD51 => Some(OptionRegion {
+            address: 0x0080_4000,
+            num_words: 4,
+            decode: atsamd51,
+        }),
+        FamilyId::NRF52840 => Some(OptionRegion {
+            address: 0x1000_1208,
+            num_words: 1,
+            decode: nrf52840,
+        }),
+        _ => None,
+    }
+}
+
+fn watchdog(software: bool) -> String {
+    if software {
+        "software".into()
+    } else {
+        "hardware".into()
+    }
+}
+
+fn enabled(bit: bool) -> String {
+    if bit {
+        "enabled".into()
+    } else {
+        "disabled".into()
+    }
+}
+
+//RDP byte then USER byte, each followed by its complement
+fn stm32f103(words: &[u32]) -> Vec<(&'static str, String)> {
+    let rdp = words[0] & 0xFF;
+    let user = (words[0] >> 16) & 0xFF;
+
+    let protection = if rdp == 0xA5 {
+        "level 0 (off)"
+    } else {
+        "level 1"
+    };
+
+    vec![
+        ("read protection", protection.into()),
+        ("watchdog", watchdog(user & 0x01 != 0)),
+    ]
+}
+
+//RDP in bits 15:8, USER in bits 7:5 of FLASH_OPTCR
+fn stm32f401(words: &[u32]) -> Vec<(&'static str, String)> {
+    let rdp = (words[0] >> 8) & 0xFF;
+
+    let protection = match rdp {
+        0xAA => "level 0 (off)",
+        0xCC => "level 2 (permanent)",
+        _ => "level 1",
+    };
+
+    vec![
+        ("read protection", protection.into()),
+        ("watchdog", watchdog(words[0] & (1 << 5) != 0)),
+    ]
+}
+
+//NVM user row, BOOTPROT 2:0, WDT enable 25, WDT always-on 26, LOCK 63:48
+fn atsamd21(words: &[u32]) -> Vec<(&'static str, String)> {
+    vec![
+        ("bootloader protection", format!("{}", words[0] & 0x7)),
+        ("watchdog", enabled(words[0] & (1 << 25) != 0)),
+        ("watchdog always-on", enabled(words[0] & (1 << 26) != 0)),
+        ("region lock", format!("0x{:04X}", words[1] >> 16)),
+    ]
+}
+
+//NVM user page, BOOTPROT 29:26, WDT enable 64, WDT always-on 65, LOCK 127:96
+fn atsamd51(words: &[u32]) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "bootloader protection",
+            format!("{}", (words[0] >> 26) & 0xF),
+        ),
+        ("watchdog", enabled(words[2] & 0x1 != 0)),
+        ("watchdog always-on", enabled(words[2] & 0x2 != 0)),
+        ("region lock", format!("0x{:08X}", words[3])),
+    ]
+}
+
+//UICR.APPROTECT, 0xFF in PALL leaves the debug port open
+fn nrf52840(words: &[u32]) -> Vec<(&'static str, String)> {
+    let protection = if words[0] & 0xFF == 0xFF {
+        "disabled"
+    } else {
+        "enabled"
+    };
+
+    vec![("access port protection", protection.into())]
+}
+
+///Read the option byte region of the connected family, or an explicit address and word count, and print what we can make of it.
+pub fn options(d: &Device, address: Option<u32>, num_words: Option<u32>) -> Result<(), Error> {
+    let bininfo = hf2::bin_info(d)?;
+
+    let region = bininfo.family_id.and_then(region);
+
+    //only decode when reading exactly the documented region for this family
+    let (address, num_words, decode) = match (region, address, num_words) {
+        (Some(region), None, None) => (region.address, region.num_words, Some(region.decode)),
+        (Some(region), address, num_words) => (
+            address.unwrap_or(region.address),
+            num_words.unwrap_or(region.num_words),
+            None,
+        ),
+        (None, Some(address), num_words) => (address, num_words.unwrap_or(1), None),
+        (None, None, _) => {
+            return Err(format!(
+                "no known option byte region for {:?}, pass --address",
+                bininfo.family_id
+            )
+            .into())
+        }
+    };
+
+    let rsp = hf2::read_words(d, address, num_words)?;
+
+    for (index, word) in rsp.words.iter().enumerate() {
+        println!("0x{:08X}: 0x{:08X}", address + index as u32 * 4, word);
+    }
+
+    if let Some(decode) = decode {
+        if rsp.words.len() == num_words as usize {
+            for (name, value) in decode(&rsp.words) {
+                println!("{}: {}", name, value);
+            }
+        }
+    }
+    Ok(())
+}
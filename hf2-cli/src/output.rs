@@ -0,0 +1,39 @@
+use log::LevelFilter;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+///Set up logging and status output from --verbose and --quiet. Each --verbose raises the log level, debug then trace, RUST_LOG still takes precedence for whatever it names.
+pub fn init(verbose: u8, quiet: bool) {
+    let mut builder = pretty_env_logger::formatted_builder();
+
+    match verbose {
+        0 => (),
+        1 => {
+            builder.filter_level(LevelFilter::Debug);
+        }
+        _ => {
+            builder.filter_level(LevelFilter::Trace);
+        }
+    }
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+///Whether status lines and the progress bar are left out, --quiet.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+///println for a line about how a command is going rather than what it read, left out with --quiet. Errors, warnings and the results a command was run for are printed regardless.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::output::quiet() {
+            println!($($arg)*);
+        }
+    };
+}
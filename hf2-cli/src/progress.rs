@@ -0,0 +1,128 @@
+use hf2::FlashEvent;
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::time::Instant;
+
+///Newline delimited json FlashEvents written to a file descriptor handed to us by a supervising process.
+#[derive(Default)]
+pub struct Progress {
+    file: Option<File>,
+}
+
+impl Progress {
+    #[cfg(unix)]
+    pub fn from_fd(fd: Option<i32>) -> io::Result<Self> {
+        use std::os::unix::io::FromRawFd;
+
+        //the parent opened it for us and it's ours to close from here on
+        let file = fd.map(|fd| unsafe { File::from_raw_fd(fd) });
+        Ok(Progress { file })
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_fd(fd: Option<i32>) -> io::Result<Self> {
+        match fd {
+            Some(_) => Err(io::ErrorKind::Unsupported.into()),
+            None => Ok(Progress::default()),
+        }
+    }
+
+    pub fn emit(&mut self, event: FlashEvent) {
+        if let Some(file) = &mut self.file {
+            let line = serde_json::to_string(&event).expect("couldn't serialize event");
+
+            //progress is best effort, a parent that stopped listening shouldn't fail the flash
+            if let Err(e) = writeln!(file, "{}", line) {
+                log::debug!("progress fd write failed {:?}", e);
+            }
+        }
+    }
+}
+
+///Width of the bar in characters.
+const BAR_WIDTH: usize = 30;
+
+///Pages written out of the pages that need writing, unchanged ones aren't counted so the bar follows the actual work. Redrawn in place with an eta on a terminal, otherwise a plain line every tenth of the way so logs stay readable.
+pub struct Bar {
+    total: u32,
+    skipped: u32,
+    written: u32,
+    start: Instant,
+    tty: bool,
+    tenths: u32,
+    quiet: bool,
+    ///whose pages these are, when several flashes share stdout
+    label: Option<String>,
+}
+
+impl Bar {
+    pub fn new(total: u32, skipped: u32) -> Self {
+        Bar {
+            total,
+            skipped,
+            written: 0,
+            start: Instant::now(),
+            tty: std::io::stdout().is_terminal(),
+            tenths: 0,
+            quiet: crate::output::quiet(),
+            label: None,
+        }
+    }
+
+    ///Same as new, always as plain lines starting with [label] since bars of flashes running side by side would draw over each other.
+    pub fn labelled(total: u32, skipped: u32, label: &str) -> Self {
+        Bar {
+            tty: false,
+            label: Some(label.to_string()),
+            ..Bar::new(total, skipped)
+        }
+    }
+
+    ///Count the pages written so far.
+    pub fn set(&mut self, written: u32) {
+        self.written = written;
+
+        if self.quiet {
+            return;
+        }
+        if self.tty {
+            self.draw();
+        } else if self.written * 10 / self.total > self.tenths || self.written == self.total {
+            self.tenths = self.written * 10 / self.total;
+            match &self.label {
+                Some(label) => {
+                    println!("[{}] {}/{} pages written", label, self.written, self.total)
+                }
+                None => println!("{}/{} pages written", self.written, self.total),
+            }
+        }
+    }
+
+    fn draw(&self) {
+        let filled = BAR_WIDTH * self.written as usize / self.total as usize;
+        let remaining = self.start.elapsed() * (self.total - self.written) / self.written;
+
+        print!(
+            "\r[{}{}] {}/{} pages, {} unchanged, eta {}s ",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            self.written,
+            self.total,
+            self.skipped,
+            remaining.as_secs()
+        );
+        //best effort like progress, a closed stdout shouldn't fail the flash
+        if let Err(e) = std::io::stdout().flush() {
+            log::debug!("progress bar flush failed {:?}", e);
+        }
+    }
+}
+
+impl Drop for Bar {
+    //whatever comes next, stats or an error, starts on a line of its own
+    fn drop(&mut self) {
+        if self.tty && !self.quiet && self.written > 0 {
+            println!();
+        }
+    }
+}
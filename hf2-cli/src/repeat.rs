@@ -0,0 +1,44 @@
+use hidapi::{HidApi, HidDevice};
+use std::ffi::CString;
+use std::time::Duration;
+
+///How often the device list is refreshed while waiting for boards to be swapped.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+///Wait for the board just flashed at path to drop off the bus, then for the next matching board to enumerate and open, however long that takes. One reporting flashed_serial is skipped, it's the same board coming back after its reset rather than a new one. Without serial numbers a board that keeps matching after its reset can't be told apart from the next.
+pub fn next_board(
+    api: &mut HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    path: &CString,
+    flashed_serial: Option<&str>,
+) -> (HidDevice, CString) {
+    status!("waiting for the board to be unplugged");
+    while hf2::matching_devices(api, vid, pid, None)
+        .any(|device_info| device_info.path() == path.as_c_str())
+    {
+        refresh(api);
+    }
+
+    status!("waiting for the next board");
+    loop {
+        let candidates = hf2::matching_devices(api, vid, pid, None).filter(|device_info| {
+            flashed_serial.is_none() || device_info.serial_number() != flashed_serial
+        });
+
+        match hf2::open_first(api, candidates) {
+            Ok((d, device_info)) => return (d, device_info.path().to_owned()),
+            //a board that enumerated but can't be opened yet is tried again next poll
+            Err(e) => log::debug!("no board to flash yet {:?}", e),
+        }
+
+        refresh(api);
+    }
+}
+
+fn refresh(api: &mut HidApi) {
+    std::thread::sleep(POLL_INTERVAL);
+    if let Err(e) = api.refresh_devices() {
+        log::debug!("refresh_devices failed {:?}", e);
+    }
+}
@@ -0,0 +1,89 @@
+use hf2::{Error, ReadWrite, SerialStream, SerialTransport};
+use hidapi::{HidApi, HidDevice};
+use std::ffi::CString;
+use std::time::Duration;
+
+///Everything the cli opens, with its options and hooks.
+pub type Device = hf2::Device<Transport>;
+
+///Where hf2 packets go, HID as the spec has it or a serial port with --port.
+pub enum Transport {
+    Hid(HidDevice),
+    Serial(SerialTransport),
+}
+
+impl Transport {
+    ///The usb serial number, serial ports don't report one.
+    pub fn serial_number(&self) -> Option<String> {
+        match self {
+            Transport::Hid(hid) => hid.get_serial_number_string().ok().flatten(),
+            Transport::Serial(_) => None,
+        }
+    }
+
+    ///Manufacturer and product for humans.
+    pub fn describe(&self) -> String {
+        match self {
+            Transport::Hid(hid) => format!(
+                "{:?} {:?}",
+                hid.get_manufacturer_string(),
+                hid.get_product_string()
+            ),
+            Transport::Serial(_) => "serial port".to_string(),
+        }
+    }
+}
+
+///Where a Transport was opened from, to open the same device again.
+pub enum Location {
+    Hid(CString),
+    Port(String),
+}
+
+impl Location {
+    pub fn open(&self, api: &HidApi) -> Result<Transport, crate::Error> {
+        Ok(match self {
+            Location::Hid(path) => api.open_path(path)?.into(),
+            Location::Port(port) => SerialTransport::open(port)?.into(),
+        })
+    }
+}
+
+impl From<HidDevice> for Transport {
+    fn from(hid: HidDevice) -> Self {
+        Transport::Hid(hid)
+    }
+}
+
+impl From<SerialTransport> for Transport {
+    fn from(port: SerialTransport) -> Self {
+        Transport::Serial(port)
+    }
+}
+
+impl ReadWrite for Transport {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        match self {
+            Transport::Hid(hid) => hid.hf2_write(data),
+            Transport::Serial(port) => port.hf2_write(data),
+        }
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Transport::Hid(hid) => hid.hf2_read(buf),
+            Transport::Serial(port) => port.hf2_read(buf),
+        }
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        match self {
+            Transport::Hid(hid) => hid.hf2_read_timeout(buf, timeout),
+            Transport::Serial(port) => port.hf2_read_timeout(buf, timeout),
+        }
+    }
+    fn serial(&self, stream: SerialStream, data: &[u8]) {
+        match self {
+            Transport::Hid(hid) => hid.serial(stream, data),
+            Transport::Serial(port) => port.serial(stream, data),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use hidapi::{HidApi, HidDevice};
+use std::time::{Duration, Instant};
+
+///What a single poll saw.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    ///nothing matching enumerates, usually mid reset
+    Gone,
+    ///enumerates and opens but bininfo fails
+    Silent,
+    Mode(hf2::BinInfoMode),
+}
+
+///Poll bininfo every interval and print a timestamped line whenever the mode changes, until interrupted. The device is re-enumerated whenever it stops answering since it usually disappears across a reset.
+pub fn watch_mode(
+    api: &mut HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    interval: Duration,
+) -> ! {
+    let start = Instant::now();
+    let mut device: Option<HidDevice> = None;
+    let mut last = None;
+
+    loop {
+        if device.is_none() {
+            if let Err(e) = api.refresh_devices() {
+                log::debug!("refresh_devices failed {:?}", e);
+            }
+            device = find(api, vid, pid, serial);
+        }
+
+        let state = match &device {
+            None => State::Gone,
+            Some(d) => match hf2::bin_info(d) {
+                Ok(bininfo) => State::Mode(bininfo.mode),
+                Err(e) => {
+                    log::debug!("bin_info failed {:?}", e);
+                    //the handle is likely stale, look for it again next poll
+                    device = None;
+                    State::Silent
+                }
+            },
+        };
+
+        if last != Some(state) {
+            let elapsed = start.elapsed().as_secs_f64();
+            match state {
+                State::Gone => println!("[{:>9.3}s] gone", elapsed),
+                State::Silent => println!("[{:>9.3}s] not answering bininfo", elapsed),
+                State::Mode(mode) => println!("[{:>9.3}s] {:?}", elapsed, mode),
+            }
+            last = Some(state);
+        }
+
+        std::thread::sleep(interval);
+    }
+}
+
+fn find(
+    api: &HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+) -> Option<HidDevice> {
+    hf2::open_first(api, hf2::matching_devices(api, vid, pid, serial))
+        .ok()
+        .map(|(d, _)| d)
+}
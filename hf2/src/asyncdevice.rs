@@ -0,0 +1,197 @@
+use crate::{
+    bin_info, checksum_pages, write_flash_page, BinInfoResponse, ChecksumPagesResponse, Error,
+    FlashStats, Flasher, FlasherConfig, Mismatch, ReadWrite,
+};
+use futures_channel::{mpsc, oneshot};
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::mpsc as std_mpsc;
+
+type Job<D> = Box<dyn FnOnce(&D) + Send>;
+
+///A device owned by a worker thread of its own, so commands can be awaited instead of blocking the caller. Commands run one at a time in the order they were issued. Works with any executor, nothing here depends on a runtime.
+pub struct AsyncDevice<D> {
+    jobs: std_mpsc::Sender<Job<D>>,
+    device: PhantomData<fn() -> D>,
+}
+
+impl<D: ReadWrite + 'static> AsyncDevice<D> {
+    ///Start the worker thread and open the device on it with open, which means the device itself doesn't need to be Send. The thread exits once the AsyncDevice is dropped.
+    pub async fn open<F>(open: F) -> Result<Self, Error>
+    where
+        F: FnOnce() -> Result<D, Error> + Send + 'static,
+    {
+        let (jobs, queue) = std_mpsc::channel::<Job<D>>();
+        let (opened, result) = oneshot::channel();
+
+        std::thread::spawn(move || {
+            let d = match open() {
+                Ok(d) => {
+                    let _ = opened.send(Ok(()));
+                    d
+                }
+                Err(e) => {
+                    let _ = opened.send(Err(e));
+                    return;
+                }
+            };
+
+            for job in queue {
+                job(&d);
+            }
+        });
+
+        result.await.map_err(|_| Error::WorkerGone)??;
+
+        Ok(AsyncDevice {
+            jobs,
+            device: PhantomData,
+        })
+    }
+
+    ///Run f against the device on the worker thread. Everything else is built on this, use it for commands without an async version.
+    pub fn run<T, F>(&self, f: F) -> impl Future<Output = Result<T, Error>>
+    where
+        F: FnOnce(&D) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        let queued = self
+            .jobs
+            .send(Box::new(move |d: &D| {
+                let _ = done.send(f(d));
+            }))
+            .is_ok();
+
+        async move {
+            if !queued {
+                return Err(Error::WorkerGone);
+            }
+            result.await.map_err(|_| Error::WorkerGone)?
+        }
+    }
+
+    pub async fn bin_info(&self) -> Result<BinInfoResponse, Error> {
+        self.run(|d| bin_info(d)).await
+    }
+
+    pub async fn checksum_pages(
+        &self,
+        target_address: u32,
+        num_pages: u32,
+    ) -> Result<ChecksumPagesResponse, Error> {
+        self.run(move |d| checksum_pages(d, target_address, num_pages))
+            .await
+    }
+
+    pub async fn write_flash_page(&self, target_address: u32, data: Vec<u8>) -> Result<(), Error> {
+        self.run(move |d| write_flash_page(d, target_address, data))
+            .await
+    }
+
+    ///Flasher::flash with a Flasher made from config.
+    pub async fn flash(
+        &self,
+        base: u32,
+        data: Vec<u8>,
+        skip_checksum: bool,
+        config: FlasherConfig,
+    ) -> Result<FlashStats, Error> {
+        self.run(move |d| Flasher::with_config(d, config)?.flash(base, &data, skip_checksum))
+            .await
+    }
+
+    ///Flasher::flash_with_progress, the progress arriving as (pages written, pages to write) on the returned stream, which ends with the flash.
+    pub fn flash_with_progress(
+        &self,
+        base: u32,
+        data: Vec<u8>,
+        skip_checksum: bool,
+        config: FlasherConfig,
+    ) -> (
+        mpsc::UnboundedReceiver<(u32, u32)>,
+        impl Future<Output = Result<FlashStats, Error>>,
+    ) {
+        let (progress, events) = mpsc::unbounded();
+
+        let result = self.run(move |d| {
+            Flasher::with_config(d, config)?.flash_with_progress(
+                base,
+                &data,
+                skip_checksum,
+                |written, total| {
+                    let _ = progress.unbounded_send((written, total));
+                },
+            )
+        });
+
+        (events, result)
+    }
+
+    ///Flasher::verify with a Flasher made from config.
+    pub async fn verify(
+        &self,
+        base: u32,
+        data: Vec<u8>,
+        config: FlasherConfig,
+    ) -> Result<Vec<Mismatch>, Error> {
+        self.run(move |d| Flasher::with_config(d, config)?.verify(base, &data))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+    use futures_executor::block_on;
+    use futures_util::StreamExt;
+
+    ///bootloader mode with 1024 pages of 16 bytes, then a response for each write
+    fn mock(writes: usize) -> Result<Mock, Error> {
+        let mock = Mock::default();
+        let mut bininfo = vec![];
+        for word in &[1_u32, 16, 1024, 64] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+        for _ in 0..writes {
+            mock.push_response(&[]);
+        }
+        Ok(mock)
+    }
+
+    #[test]
+    fn commands_run_on_worker() {
+        block_on(async {
+            let d = AsyncDevice::open(|| mock(1)).await.unwrap();
+
+            assert_eq!(d.bin_info().await.unwrap().flash_page_size, 16);
+            d.write_flash_page(0x2000, vec![0xAA; 16]).await.unwrap();
+
+            let writes = d.run(|mock: &Mock| Ok(mock.writes.borrow().len())).await;
+            assert_eq!(writes.unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn flash_progress_stream() {
+        block_on(async {
+            let d = AsyncDevice::open(|| mock(2)).await.unwrap();
+
+            let (events, result) =
+                d.flash_with_progress(0x2000, vec![0xAA; 32], true, FlasherConfig::default());
+
+            assert_eq!(result.await.unwrap().pages_written, 2);
+            assert_eq!(events.collect::<Vec<_>>().await, vec![(1, 2), (2, 2)]);
+        });
+    }
+
+    #[test]
+    fn open_error() {
+        block_on(async {
+            let opened = AsyncDevice::<Mock>::open(|| Err(Error::NotFound)).await;
+            assert!(matches!(opened, Err(Error::NotFound)));
+        });
+    }
+}
@@ -1,9 +1,12 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
+use alloc::vec;
 use core::convert::TryFrom;
+use core::fmt;
 use scroll::{ctx, Pread, LE};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BinInfoMode {
     //bootloader, and thus flashing of user-space programs is allowed
     Bootloader = 0x0001,
@@ -24,22 +27,15 @@ impl TryFrom<u32> for BinInfoMode {
 }
 
 /// This command states the current mode of the device:
-pub fn bin_info(d: &hidapi::HidDevice) -> Result<BinInfoResponse, Error> {
-    xmit(Command::new(0x0001, 0, vec![]), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => return (data.as_slice()).pread_with(0, LE),
-        Ok(_) => return Err(Error::CommandNotRecognized),
-        Err(e) => return Err(e),
-    }
+pub fn bin_info(d: &impl ReadWrite) -> Result<BinInfoResponse, Error> {
+    let rsp = send(&Command::new(0x0001, vec![]), d)?;
+
+    rsp.data.as_slice().pread_with(0, LE)
 }
 
 ///Response to the bin_info command
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinInfoResponse {
     pub mode: BinInfoMode, //    uint32_t mode;
     pub flash_page_size: u32,
@@ -50,6 +46,7 @@ pub struct BinInfoResponse {
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FamilyId {
     ATSAMD21,
     ATSAMD51,
@@ -76,6 +73,46 @@ impl From<u32> for FamilyId {
     }
 }
 
+impl From<FamilyId> for u32 {
+    fn from(family_id: FamilyId) -> Self {
+        match family_id {
+            FamilyId::ATSAMD21 => 0x68ed_2b88,
+            FamilyId::ATSAMD51 => 0x5511_4460,
+            FamilyId::NRF52840 => 0x1b57_745f,
+            FamilyId::STM32F103 => 0x5ee2_1072,
+            FamilyId::STM32F401 => 0x5775_5a57,
+            FamilyId::ATMEGA32 => 0x1657_3617,
+            FamilyId::CYPRESS_FX2 => 0x5a18_069b,
+            FamilyId::UNKNOWN(val) => val,
+        }
+    }
+}
+
+///Name and id, or just the id of an unknown family.
+impl fmt::Display for FamilyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FamilyId::UNKNOWN(val) => write!(f, "{:#010X}", val),
+            family_id => write!(f, "{:?} ({:#010X})", family_id, u32::from(*family_id)),
+        }
+    }
+}
+
+impl BinInfoResponse {
+    ///Error::FamilyMismatch when an image built for image_family would land on a device reporting a different one. Fine when either doesn't say.
+    pub fn check_family(&self, image_family: Option<FamilyId>) -> Result<(), Error> {
+        match (image_family, self.family_id) {
+            (Some(image), Some(device)) if image != device => {
+                Err(Error::FamilyMismatch { image, device })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+///Smallest max_message_size with room for a checksum_pages response holding a single checksum.
+pub const MIN_MAX_MESSAGE_SIZE: u32 = 6;
+
 impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
@@ -92,6 +129,16 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
         let flash_num_pages = this.gread_with::<u32>(&mut offset, le)?;
         let max_message_size = this.gread_with::<u32>(&mut offset, le)?;
 
+        //everything sized from these would divide by zero or underflow
+        if flash_page_size == 0 {
+            return Err(Error::InvalidBinInfo("flash page size is 0"));
+        }
+        if max_message_size < MIN_MAX_MESSAGE_SIZE {
+            return Err(Error::InvalidBinInfo(
+                "max message size has no room for a single checksum",
+            ));
+        }
+
         let family_id = if this.len() >= 20 {
             let family_id: FamilyId = this.gread_with::<u32>(&mut offset, le)?.into();
             Some(family_id)
@@ -111,3 +158,102 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+    use alloc::string::ToString;
+
+    #[test]
+    fn bin_info_packs_and_parses() {
+        let mock = Mock::default();
+        let mut data = vec![];
+        for word in &[1_u32, 256, 1024, 512, 0x5511_4460] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&data);
+
+        assert_eq!(
+            bin_info(&mock).unwrap(),
+            BinInfoResponse {
+                mode: BinInfoMode::Bootloader,
+                flash_page_size: 256,
+                flash_num_pages: 1024,
+                max_message_size: 512,
+                family_id: Some(FamilyId::ATSAMD51),
+            }
+        );
+
+        //report id, final packet header, command id, tag and reserved with no data
+        let tag = mock.tag();
+        assert_eq!(
+            mock.writes.borrow()[0],
+            vec![0x00, 0x48, 0x01, 0x00, 0x00, 0x00, tag[0], tag[1], 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn check_family_only_when_both_say() {
+        let bininfo = BinInfoResponse {
+            mode: BinInfoMode::Bootloader,
+            flash_page_size: 256,
+            flash_num_pages: 1024,
+            max_message_size: 512,
+            family_id: Some(FamilyId::ATSAMD51),
+        };
+
+        assert!(bininfo.check_family(Some(FamilyId::ATSAMD51)).is_ok());
+        assert!(bininfo.check_family(None).is_ok());
+
+        let error = bininfo.check_family(Some(FamilyId::ATSAMD21)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "image is built for ATSAMD21 (0x68ED2B88) but the device reports ATSAMD51 (0x55114460)"
+        );
+
+        let bininfo = BinInfoResponse {
+            family_id: None,
+            ..bininfo
+        };
+        assert!(bininfo.check_family(Some(FamilyId::UNKNOWN(1))).is_ok());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let bininfo = BinInfoResponse {
+            mode: BinInfoMode::Bootloader,
+            flash_page_size: 256,
+            flash_num_pages: 1024,
+            max_message_size: 512,
+            family_id: Some(FamilyId::UNKNOWN(0x1234_5678)),
+        };
+
+        let json = serde_json::to_string(&bininfo).unwrap();
+        assert_eq!(
+            serde_json::from_str::<BinInfoResponse>(&json).unwrap(),
+            bininfo
+        );
+    }
+
+    #[test]
+    fn rejects_unusable_bininfo() {
+        let parse = |page_size: u32, max_message_size: u32| {
+            let mut data = vec![];
+            for word in &[1_u32, page_size, 1024, max_message_size] {
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+            data.pread_with::<BinInfoResponse>(0, LE)
+        };
+
+        for max_message_size in &[0, 2, 4] {
+            assert!(matches!(
+                parse(256, *max_message_size),
+                Err(Error::InvalidBinInfo(_))
+            ));
+        }
+        assert!(matches!(parse(0, 512), Err(Error::InvalidBinInfo(_))));
+        assert_eq!(parse(256, 6).unwrap().max_message_size, 6);
+    }
+}
@@ -1,10 +1,25 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{bin_info, changed_runs, pages, BinInfoResponse, Error, ReadWrite};
+use alloc::{vec, vec::Vec};
 use scroll::{ctx, Pread, Pwrite, LE};
 
+///A page whose checksum on the device doesn't match the image.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Mismatch {
+    ///counting from the first page of the image
+    pub page_index: u32,
+    pub address: u32,
+    pub device_crc: u16,
+    pub file_crc: u16,
+}
+
+///How many times checksum_pages repeats a request whose response doesn't hold one checksum per page.
+pub const CHECKSUM_RETRIES: usize = 3;
+
 ///Compute checksum of a number of pages. Maximum value for num_pages is max_message_size / 2 - 2. The checksum algorithm used is CRC-16-CCITT.
 pub fn checksum_pages(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     num_pages: u32,
 ) -> Result<ChecksumPagesResponse, Error> {
@@ -14,17 +29,252 @@ pub fn checksum_pages(
     buffer.gwrite_with(target_address, &mut offset, scroll::LE)?;
     buffer.gwrite_with(num_pages, &mut offset, scroll::LE)?;
 
-    xmit(Command::new(0x0007, 0, buffer), d)?;
+    let command = Command::new(0x0007, buffer);
+    let mut retries = CHECKSUM_RETRIES;
+
+    loop {
+        let rsp: ChecksumPagesResponse = send(&command, d)?.data.as_slice().pread_with(0, LE)?;
+
+        //a dropped report leaves us short, ask again rather than hand back a partial list
+        if rsp.checksums.len() == num_pages as usize {
+            return Ok(rsp);
+        }
+
+        log::warn!(
+            "checksum_pages @{:08X} returned {} checksums, expected {}",
+            target_address,
+            rsp.checksums.len(),
+            num_pages
+        );
+
+        if retries == 0 {
+            return Err(Error::Parse);
+        }
+        retries -= 1;
+    }
+}
+
+///Checksum num_pages pages starting at target_address, split into as many checksum_pages requests as max_message_size requires.
+pub fn checksum_region(
+    d: &impl ReadWrite,
+    target_address: u32,
+    num_pages: u32,
+) -> Result<Vec<u16>, Error> {
+    checksum_region_iter(d, target_address, num_pages)?.collect()
+}
+
+///Lazy checksum_region, fetching one checksum_pages request worth at a time as the iterator is drained. Only a single response is held in memory instead of one u16 per page, which adds up for multi megabyte flashes.
+pub fn checksum_region_iter<D: ReadWrite>(
+    d: &D,
+    target_address: u32,
+    num_pages: u32,
+) -> Result<ChecksumRegionIter<'_, D>, Error> {
+    let bininfo = bin_info(d)?;
+
+    Ok(ChecksumRegionIter::new(
+        d,
+        target_address,
+        num_pages,
+        bininfo.flash_page_size,
+        max_checksum_pages(&bininfo)?,
+    ))
+}
+
+///Most pages a single checksum_pages response has room for.
+pub(crate) fn max_checksum_pages(bininfo: &BinInfoResponse) -> Result<u32, Error> {
+    match (bininfo.max_message_size / 2).checked_sub(2) {
+        Some(max_pages) if max_pages > 0 => Ok(max_pages),
+        _ => Err(Error::InvalidBinInfo(
+            "max message size has no room for a single checksum",
+        )),
+    }
+}
+
+///Iterator returned by checksum_region_iter, yields a checksum per page and stops after the first error.
+pub struct ChecksumRegionIter<'a, D: ReadWrite> {
+    d: &'a D,
+    target_address: u32,
+    num_pages: u32,
+    flash_page_size: u32,
+    max_pages: u32,
+    page_index: u32,
+    chunk: alloc::vec::IntoIter<u16>,
+}
+
+impl<'a, D: ReadWrite> ChecksumRegionIter<'a, D> {
+    pub(crate) fn new(
+        d: &'a D,
+        target_address: u32,
+        num_pages: u32,
+        flash_page_size: u32,
+        max_pages: u32,
+    ) -> Self {
+        ChecksumRegionIter {
+            d,
+            target_address,
+            num_pages,
+            flash_page_size,
+            max_pages,
+            page_index: 0,
+            chunk: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl<'a, D: ReadWrite> Iterator for ChecksumRegionIter<'a, D> {
+    type Item = Result<u16, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(checksum) = self.chunk.next() {
+            return Some(Ok(checksum));
+        }
+
+        if self.page_index >= self.num_pages {
+            return None;
+        }
+
+        let count = (self.num_pages - self.page_index).min(self.max_pages);
+        let address = self.target_address + self.flash_page_size * self.page_index;
+
+        match checksum_pages(self.d, address, count) {
+            Ok(chk) => {
+                self.page_index += count;
+                self.chunk = chk.checksums.into_iter();
+                self.chunk.next().map(Ok)
+            }
+            Err(e) => {
+                //nothing sensible follows a failed chunk
+                self.page_index = self.num_pages;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+///Value a byte of flash reads as once erased, for every family we know of.
+pub const ERASED_BYTE: u8 = 0xFF;
+
+///Whether each page of data written at address differs from the device, comparing page checksums read in as few requests as max_message_size allows.
+pub fn changed_pages(
+    d: &impl ReadWrite,
+    bininfo: &BinInfoResponse,
+    address: u32,
+    data: &[u8],
+) -> Result<Vec<bool>, Error> {
+    changed_pages_with_progress(d, bininfo, address, data, |_, _| {})
+}
+
+///Same as changed_pages, calling progress with the address and page count of every checksum request.
+pub fn changed_pages_with_progress<F>(
+    d: &impl ReadWrite,
+    bininfo: &BinInfoResponse,
+    address: u32,
+    data: &[u8],
+    mut progress: F,
+) -> Result<Vec<bool>, Error>
+where
+    F: FnMut(u32, u32),
+{
+    let num_pages = bininfo.pages(data, address).len() as u32;
+    let max_pages = max_checksum_pages(bininfo)?;
+    let mut device_checksums = Vec::with_capacity(num_pages as usize);
+
+    for first in (0..num_pages).step_by(max_pages as usize) {
+        let target_address = address + first * bininfo.flash_page_size;
+        let count = (num_pages - first).min(max_pages);
+
+        let chk = checksum_pages(d, target_address, count)?;
+        device_checksums.extend_from_slice(&chk.checksums);
+        progress(target_address, count);
+    }
+    log::debug!("checksums received {:04X?}", device_checksums);
+
+    Ok(page_checksums(data, bininfo.flash_page_size)
+        .into_iter()
+        .zip(device_checksums)
+        .map(|(file, device)| file != device)
+        .collect())
+}
+
+///Checksum again the pages of data at address marked in written, in as few requests as max_message_size allows, returning every one that doesn't match data. A page can be acknowledged and still not commit on some flash controllers.
+pub fn written_mismatches(
+    d: &impl ReadWrite,
+    bininfo: &BinInfoResponse,
+    address: u32,
+    data: &[u8],
+    written: &[bool],
+) -> Result<Vec<Mismatch>, Error> {
+    let page_size = bininfo.flash_page_size;
+    let file_crcs = page_checksums(data, page_size);
+    let mut mismatches = vec![];
+
+    for run in changed_runs(written, max_checksum_pages(bininfo)?) {
+        let target_address = address + run.start as u32 * page_size;
+        let chk = checksum_pages(d, target_address, run.len() as u32)?;
+
+        for (page_index, device_crc) in run.zip(chk.checksums) {
+            let file_crc = file_crcs[page_index];
+
+            if device_crc != file_crc {
+                mismatches.push(Mismatch {
+                    page_index: page_index as u32,
+                    address: address + page_index as u32 * page_size,
+                    device_crc,
+                    file_crc,
+                });
+            }
+        }
+    }
+
+    Ok(mismatches)
+}
+
+///Indices of the num_pages pages from address whose checksum differs from an erased page, ie those holding data.
+pub fn dirty_pages(d: &impl ReadWrite, address: u32, num_pages: u32) -> Result<Vec<u32>, Error> {
+    let bininfo = bin_info(d)?;
+    let erased = crc16(&vec![ERASED_BYTE; bininfo.flash_page_size as usize]);
+
+    dirty(checksum_region_iter(d, address, num_pages)?, erased)
+}
+
+fn dirty<I>(checksums: I, erased: u16) -> Result<Vec<u32>, Error>
+where
+    I: Iterator<Item = Result<u16, Error>>,
+{
+    let mut dirty = vec![];
+
+    for (index, checksum) in checksums.enumerate() {
+        if checksum? != erased {
+            dirty.push(index as u32);
+        }
+    }
+
+    Ok(dirty)
+}
+
+///The checksum the device should report for each page_size page of data, the last one padded with zeros, to diff against checksum_pages yourself.
+pub fn page_checksums(data: &[u8], page_size: u32) -> Vec<u16> {
+    pages(data, 0, page_size)
+        .map(|(_, page)| crc16(&page))
+        .collect()
+}
+
+///CRC-16-CCITT as computed by checksum_pages, polynomial 0x1021 starting from 0, aka XMODEM.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0_u16;
 
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => return (data.as_slice()).pread_with(0, LE),
-        Ok(_) => return Err(Error::CommandNotRecognized),
-        Err(e) => return Err(e),
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
     }
+
+    crc
 }
 
 ///Response to the checksum_pages command
@@ -48,3 +298,135 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for ChecksumPagesResponse {
         Ok((ChecksumPagesResponse { checksums }, offset))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+
+    #[test]
+    fn checksum_pages_packs_request() {
+        let mock = Mock::default();
+        mock.push_response(&[0x34, 0x12]);
+
+        checksum_pages(&mock, 0x4000, 1).unwrap();
+
+        //report id, final packet header, command id, tag, reserved, then LE address and page count
+        let tag = mock.tag();
+        assert_eq!(
+            mock.writes.borrow()[0],
+            vec![
+                0x00, 0x50, 0x07, 0x00, 0x00, 0x00, tag[0], tag[1], 0x00, 0x00, 0x00, 0x40, 0x00,
+                0x00, 0x01, 0x00, 0x00, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_pages_compares_crcs() {
+        let bininfo = BinInfoResponse {
+            mode: crate::BinInfoMode::Bootloader,
+            flash_page_size: 4,
+            flash_num_pages: 64,
+            //room for 2 checksums per request
+            max_message_size: 8,
+            family_id: None,
+        };
+        let data = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+        let mock = Mock::default();
+        let mut first = crc16(&data[..4]).to_le_bytes().to_vec();
+        first.extend_from_slice(&0_u16.to_le_bytes());
+        mock.push_response(&first);
+        mock.push_response(&crc16(&[9, 0, 0, 0]).to_le_bytes());
+
+        let mut requests = vec![];
+        let changed = changed_pages_with_progress(&mock, &bininfo, 0x100, &data, |from, count| {
+            requests.push((from, count))
+        })
+        .unwrap();
+
+        assert_eq!(changed, vec![false, true, false]);
+        assert_eq!(requests, vec![(0x100, 2), (0x108, 1)]);
+    }
+
+    #[test]
+    fn retry_short_response() {
+        let mock = Mock::default();
+
+        //first response lost a checksum, second is complete
+        mock.push_response(&[0x34, 0x12]);
+        mock.push_response(&[0x34, 0x12, 0x78, 0x56]);
+
+        let chk = checksum_pages(&mock, 0x4000, 2).unwrap();
+
+        assert_eq!(chk.checksums, vec![0x1234, 0x5678]);
+        assert_eq!(mock.writes.borrow().len(), 2);
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let mock = Mock::default();
+
+        for _ in 0..=CHECKSUM_RETRIES {
+            mock.push_response(&[0x34, 0x12]);
+        }
+
+        assert!(checksum_pages(&mock, 0x4000, 2).is_err());
+        assert_eq!(mock.writes.borrow().len(), CHECKSUM_RETRIES + 1);
+    }
+
+    #[test]
+    fn region_iter_chunks_lazily() {
+        let mock = Mock::default();
+
+        mock.push_response(&[0x01, 0x00, 0x02, 0x00]);
+        mock.push_response(&[0x03, 0x00]);
+
+        let mut iter = ChecksumRegionIter::new(&mock, 0x4000, 3, 256, 2);
+
+        assert_eq!(iter.next().unwrap().unwrap(), 1);
+        assert_eq!(mock.writes.borrow().len(), 1);
+        assert_eq!(iter.next().unwrap().unwrap(), 2);
+        assert_eq!(iter.next().unwrap().unwrap(), 3);
+        assert_eq!(mock.writes.borrow().len(), 2);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn page_checksums_pad_last_page() {
+        let data = [1, 2, 3, 4, 5];
+        assert_eq!(
+            page_checksums(&data, 4),
+            vec![crc16(&[1, 2, 3, 4]), crc16(&[5, 0, 0, 0])]
+        );
+        assert!(page_checksums(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn dirty_against_erased() {
+        let erased = crc16(&[ERASED_BYTE; 256]);
+        let checksums = vec![Ok(erased), Ok(0x1234), Ok(erased), Ok(0)];
+
+        assert_eq!(dirty(checksums.into_iter(), erased).unwrap(), vec![1, 3]);
+    }
+
+    #[test]
+    fn region_iter_stops_on_error() {
+        let mock = Mock::default();
+
+        for _ in 0..=CHECKSUM_RETRIES {
+            mock.push_response(&[0x01, 0x00]);
+        }
+
+        let mut iter = ChecksumRegionIter::new(&mock, 0x4000, 4, 256, 2);
+
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+}
@@ -1,26 +1,13 @@
+use crate::packet::{self, Reassembled, Reassembler};
 use crate::{Error, ReadWrite};
+use alloc::borrow::Cow;
+use alloc::{vec, vec::Vec};
 use core::convert::TryFrom;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
 
 use scroll::{ctx, Pread, Pwrite, LE};
 
-impl From<scroll::Error> for Error {
-    fn from(_err: scroll::Error) -> Self {
-        Error::Parse
-    }
-}
-
-impl From<core::str::Utf8Error> for Error {
-    fn from(_err: core::str::Utf8Error) -> Self {
-        Error::Parse
-    }
-}
-
-impl From<std::io::Error> for Error {
-    fn from(_err: std::io::Error) -> Self {
-        Error::Arguments
-    }
-}
-
 #[derive(Debug, PartialEq)]
 pub(crate) struct CommandResponse {
     ///arbitrary number set by the host, for example as sequence number. The response should repeat the tag.
@@ -32,16 +19,28 @@ pub(crate) struct CommandResponse {
     pub(crate) data: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum CommandResponseStatus {
-    //command understood and executed correctly
+///Status a device answers a command with.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum CommandResponseStatus {
+    ///command understood and executed correctly
     Success = 0x00,
-    //command not understood
+    ///command not understood
     ParseError = 0x01,
-    //command execution error
+    ///command execution error
     ExecutionError = 0x02,
 }
 
+impl fmt::Display for CommandResponseStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandResponseStatus::Success => write!(f, "success"),
+            CommandResponseStatus::ParseError => write!(f, "command not understood"),
+            CommandResponseStatus::ExecutionError => write!(f, "execution error"),
+        }
+    }
+}
+
 impl TryFrom<u8> for CommandResponseStatus {
     type Error = Error;
 
@@ -55,30 +54,11 @@ impl TryFrom<u8> for CommandResponseStatus {
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum PacketType {
-    //Inner packet of a command message
-    Inner = 0,
-    //Final packet of a command message
-    Final = 1,
-    //Serial stdout
-    StdOut = 2,
-    //Serial stderr
-    Stderr = 3,
-}
-
-impl TryFrom<u8> for PacketType {
-    type Error = Error;
-
-    fn try_from(val: u8) -> Result<Self, Self::Error> {
-        match val {
-            0 => Ok(PacketType::Inner),
-            1 => Ok(PacketType::Final),
-            2 => Ok(PacketType::StdOut),
-            3 => Ok(PacketType::Stderr),
-            _ => Err(Error::Parse),
-        }
-    }
+///Which of the device's serial streams a packet of output came from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SerialStream {
+    Stdout,
+    Stderr,
 }
 
 // doesnt know what the data is supposed to be decoded as
@@ -108,8 +88,32 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for CommandResponse {
     }
 }
 
+///Some bootloader firmwares prefix character array payloads (info, dmesg) with their length. When the leading
+///u32 or u16 LE equals exactly the number of bytes that follow it the prefix is a header and is stripped, otherwise
+///the payload is returned untouched. Plain text can't satisfy this unless its first characters happen to encode
+///its own length, which would take control characters or a multi kilobyte message.
+pub(crate) fn strip_length_header(this: &[u8]) -> &[u8] {
+    if this.len() >= 4 {
+        let len = u32::from_le_bytes([this[0], this[1], this[2], this[3]]) as usize;
+        if len == this.len() - 4 {
+            log::debug!("stripping u32 length header");
+            return &this[4..];
+        }
+    }
+
+    if this.len() >= 2 {
+        let len = u16::from_le_bytes([this[0], this[1]]) as usize;
+        if len == this.len() - 2 {
+            log::debug!("stripping u16 length header");
+            return &this[2..];
+        }
+    }
+
+    this
+}
+
 #[derive(Debug)]
-pub(crate) struct Command {
+pub(crate) struct Command<'a> {
     ///Command ID
     id: u32,
     ///arbitrary number set by the host, for example as sequence number. The response should repeat the tag.
@@ -118,133 +122,164 @@ pub(crate) struct Command {
     _reserved0: u8,
     ///reserved bytes in the command should be sent as zero and ignored by the device
     _reserved1: u8,
-    ///LE bytes
-    data: Vec<u8>,
+    ///LE bytes, borrowed so a page can be sent without copying it first
+    data: Cow<'a, [u8]>,
 }
-impl Command {
-    pub(crate) fn new(id: u32, tag: u16, data: Vec<u8>) -> Self {
+//tag of the next command built. It only has to differ from the last command's on the same device, so load and
+//store are enough, which every target has, and threads racing on it just repeat a tag
+static NEXT_TAG: AtomicU16 = AtomicU16::new(0);
+
+impl<'a> Command<'a> {
+    ///A command with the next tag, rx checks the response repeats it.
+    pub(crate) fn new(id: u32, data: impl Into<Cow<'a, [u8]>>) -> Self {
+        let tag = NEXT_TAG.load(Ordering::Relaxed);
+        NEXT_TAG.store(tag.wrapping_add(1), Ordering::Relaxed);
+
         Self {
             id,
             tag,
             _reserved0: 0,
             _reserved1: 0,
-            data,
+            data: data.into(),
         }
     }
 }
 
 ///Transmit a Command, command.data should already have been LE converted
-pub(crate) fn xmit(cmd: Command, d: &impl ReadWrite) -> Result<(), Error> {
+pub(crate) fn xmit(cmd: &Command, d: &impl ReadWrite) -> Result<(), Error> {
     log::debug!("{:?}", cmd);
 
-    //Packets are up to 64 bytes long + first byte is Report ID,
-    let buffer = &mut [0_u8; 65];
-
-    // Report ID at 0, hardcoded to 0, header at 1 filled in later, so start at 2
-    let mut offset = 2;
-
     //command struct is 8 bytes
-    buffer.gwrite_with(cmd.id, &mut offset, LE)?;
-    buffer.gwrite_with(cmd.tag, &mut offset, LE)?;
-    buffer.gwrite_with(cmd._reserved0, &mut offset, LE)?;
-    buffer.gwrite_with(cmd._reserved1, &mut offset, LE)?;
-
-    //copy up to the first 55 bytes
-    let mut count = if cmd.data.len() > 55 {
-        55
-    } else {
-        cmd.data.len()
-    };
-    buffer.gwrite(&cmd.data[..count], &mut offset)?;
-
-    //subtract header from offset for packet size
-    if count == cmd.data.len() {
-        buffer[1] = (PacketType::Final as u8) << 6 | (offset - 2) as u8;
-        log::debug!("tx: {:02X?}", &buffer[..offset]);
-
-        return d.hf2_write(&buffer[..offset]).map(|_| ());
-    } else {
-        buffer[1] = (PacketType::Inner as u8) << 6 | (offset - 2) as u8;
-        log::debug!("tx: {:02X?}", &buffer[..offset]);
-
-        d.hf2_write(&buffer[..offset])?;
-    }
-
-    //send the rest in chunks up to 63
-    for chunk in cmd.data[count..].chunks(63) {
-        count += chunk.len();
-
-        if count == cmd.data.len() {
-            buffer[1] = (PacketType::Final as u8) << 6 | chunk.len() as u8;
-        } else {
-            buffer[1] = (PacketType::Inner as u8) << 6 | chunk.len() as u8;
-        }
-
-        buffer[2..(chunk.len() + 2)].copy_from_slice(chunk);
-
-        log::debug!("tx: {:02X?}", &buffer[..(chunk.len() + 2)]);
-        d.hf2_write(&buffer[..(chunk.len() + 2)])?;
+    let mut message = vec![0_u8; 8];
+    let mut offset = 0;
+    message.gwrite_with(cmd.id, &mut offset, LE)?;
+    message.gwrite_with(cmd.tag, &mut offset, LE)?;
+    message.gwrite_with(cmd._reserved0, &mut offset, LE)?;
+    message.gwrite_with(cmd._reserved1, &mut offset, LE)?;
+    message.extend_from_slice(&cmd.data);
+
+    //Packets are up to report_size bytes long + first byte is Report ID, hardcoded to 0
+    let buffer = &mut vec![0_u8; d.report_size() + 1][..];
+
+    for packet in packet::encode(&message, d.report_size()) {
+        buffer[1..=packet.len()].copy_from_slice(&packet);
+
+        log::debug!("tx: {:02X?}", &buffer[..=packet.len()]);
+        d.hf2_write(&buffer[..=packet.len()])?;
     }
     Ok(())
 }
 
-///Receive a CommandResponse, CommandResponse.data is not interpreted in any way.
-pub(crate) fn rx(d: &impl ReadWrite) -> Result<CommandResponse, Error> {
-    let mut bitsnbytes: Vec<u8> = vec![];
+///Receive the CommandResponse to the command tagged tag, CommandResponse.data is not interpreted in any way. A response with another tag, left over from an earlier command, is Error::TagMismatch. Serial output packets in between are handed to d.serial. With a transport timeout each read has to arrive within it, otherwise Error::Timeout. A packet split over several short reads is put back together before it's parsed, Error::ShortRead when its header claims more than a report can hold.
+pub(crate) fn rx(d: &impl ReadWrite, tag: u16) -> Result<CommandResponse, Error> {
+    let mut reassembler = Reassembler::default();
 
-    let buffer = &mut [0_u8; 64];
+    let buffer = &mut vec![0_u8; d.report_size()][..];
+    let mut filled = 0;
     let mut retries = 5;
 
     // keep reading until Final packet
-    'outer: while {
-        let count = d.hf2_read(buffer)?;
+    loop {
+        let count = match d.timeout() {
+            Some(timeout) => match d.hf2_read_timeout(&mut buffer[filled..], timeout)? {
+                0 => return Err(Error::Timeout),
+                count => count,
+            },
+            None => d.hf2_read(&mut buffer[filled..])?,
+        };
 
         log::debug!("rx count: {:?}", count);
 
         if count < 1 {
             if retries <= 0 {
                 return Err(Error::Parse);
-            } else {
-                retries -= 1;
-                continue 'outer;
             }
+            retries -= 1;
+            continue;
+        }
+        filled += count;
+
+        //some platforms hand a report over in pieces, wait for as much as the header says is coming
+        let claimed = (buffer[0] & 0x3F) as usize;
+        if claimed + 1 > buffer.len() {
+            return Err(Error::ShortRead {
+                claimed,
+                available: buffer.len() - 1,
+            });
+        }
+        if filled < claimed + 1 {
+            log::debug!("short read, {} of {} bytes", filled, claimed + 1);
+            continue;
         }
 
-        let ptype = PacketType::try_from(buffer[0] >> 6)?;
+        let count = core::mem::take(&mut filled);
+        log::debug!("rx: {:02X?}", &buffer[..count]);
 
-        log::debug!("rx ptype: {:?}", ptype);
+        //serial output can arrive in between the packets of a response, it isn't part of it
+        match reassembler.push(&buffer[..count])? {
+            Reassembled::Pending => (),
+            Reassembled::Serial(stream, data) => d.serial(stream, data),
+            Reassembled::Message(message) => {
+                let resp: CommandResponse = message.as_slice().pread_with(0, LE)?;
 
-        let len: usize = (buffer[0] & 0x3F) as usize;
+                log::debug!("{:?}", resp);
 
-        log::debug!("rx len: {:?}", len);
+                if resp.tag != tag {
+                    return Err(Error::TagMismatch {
+                        expected: tag,
+                        got: resp.tag,
+                    });
+                }
 
-        if len >= count {
-            return Err(Error::Parse);
+                return Ok(resp);
+            }
         }
+    }
+}
 
-        log::debug!(
-            "rx header: {:02X?} data: {:02X?}",
-            &buffer[0],
-            &buffer[1..(len + 1)]
-        );
-
-        //skip the header byte and strip excess bytes remote is allowed to send
-        bitsnbytes.extend_from_slice(&buffer[1..(len + 1)]);
+///Transmit cmd and receive its response, a status other than success is returned as Error::Status. The command is sent again up to d.retries() more times when the response times out or reports a failure, except that it isn't understood, which resending won't change.
+pub(crate) fn send(cmd: &Command, d: &impl ReadWrite) -> Result<CommandResponse, Error> {
+    let mut attempts = 0;
 
-        //funky do while notation
-        ptype == PacketType::Inner
-    } {}
+    loop {
+        xmit(cmd, d)?;
 
-    let resp = bitsnbytes.as_slice().pread_with(0, LE)?;
+        let result = match rx(d, cmd.tag) {
+            Ok(rsp) if rsp.status != CommandResponseStatus::Success => Err(Error::Status {
+                command: cmd.id,
+                status: rsp.status,
+                status_info: rsp.status_info,
+            }),
+            result => result,
+        };
 
-    log::debug!("{:?}", resp);
+        match result {
+            Err(e @ Error::Status { .. }) | Err(e @ Error::Timeout)
+                if attempts < d.retries() && !e.is_unsupported() =>
+            {
+                attempts += 1;
+                log::warn!(
+                    "command {:#06X} failed: {}, retry {} of {}",
+                    cmd.id,
+                    e,
+                    attempts,
+                    d.retries()
+                );
+            }
+            result => return result,
+        }
+    }
+}
 
-    Ok(resp)
+///Send a command the crate doesn't know, such as a vendor extension, with data as its already LE encoded payload. Returns the response payload, a status other than success is Error::Status with the status and its info.
+pub fn send_raw(d: &impl ReadWrite, command_id: u32, data: &[u8]) -> Result<Vec<u8>, Error> {
+    send(&Command::new(command_id, data), d).map(|rsp| rsp.data)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::string::ToString;
 
     #[allow(dead_code)]
     pub struct MyMock<R, W>
@@ -262,7 +297,7 @@ mod tests {
         W: Fn(&[u8]) -> usize,
     {
         fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
-            let len = (&self.writer)(data);
+            let len = (self.writer)(data);
 
             Ok(len)
         }
@@ -355,9 +390,12 @@ mod tests {
             writer,
         };
 
-        let command = Command::new(0x0006, 4, le_page);
+        let command = Command {
+            tag: 4,
+            ..Command::new(0x0006, le_page)
+        };
 
-        xmit(command, &mock).unwrap();
+        xmit(&command, &mock).unwrap();
     }
 
     #[test]
@@ -409,7 +447,187 @@ mod tests {
             data: result.to_vec(),
         };
 
-        let rsp = rx(&mock).unwrap();
+        let rsp = rx(&mock, 4).unwrap();
         assert_eq!(rsp, response);
     }
+
+    #[test]
+    fn send_raw_round_trip() {
+        let mock = crate::mock::Mock::default();
+        mock.push_response(&[0xAA, 0xBB]);
+        mock.push_failure(CommandResponseStatus::ExecutionError, 7);
+
+        assert_eq!(send_raw(&mock, 0x8001, &[1, 2]).unwrap(), vec![0xAA, 0xBB]);
+        assert!(matches!(
+            send_raw(&mock, 0x8001, &[]),
+            Err(Error::Status {
+                command: 0x8001,
+                status: CommandResponseStatus::ExecutionError,
+                status_info: 7
+            })
+        ));
+
+        let first = &mock.writes.borrow()[0];
+        assert_eq!(first[..6], [0x00, 0x4A, 0x01, 0x80, 0x00, 0x00]);
+        assert_eq!(first[8..], [0x00, 0x00, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn send_retries_failed_status() {
+        let mock = crate::mock::Mock::default().with_retries(2);
+        mock.push_failure(CommandResponseStatus::ExecutionError, 0);
+        mock.push_response(&[]);
+
+        let rsp = send(&Command::new(0x0006, vec![1, 2, 3, 4]), &mock).unwrap();
+        assert_eq!(rsp.status, CommandResponseStatus::Success);
+
+        //the same command went out twice
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0], writes[1]);
+    }
+
+    #[test]
+    fn send_gives_up_after_retries() {
+        let mock = crate::mock::Mock::default().with_retries(1);
+        for _ in 0..3 {
+            mock.push_failure(CommandResponseStatus::ExecutionError, 0);
+        }
+
+        assert!(matches!(
+            send(&Command::new(0x0006, vec![]), &mock),
+            Err(Error::Status {
+                command: 0x0006,
+                status: CommandResponseStatus::ExecutionError,
+                status_info: 0,
+            })
+        ));
+        assert_eq!(mock.writes.borrow().len(), 2);
+    }
+
+    #[test]
+    fn stale_response_tag() {
+        let mock = crate::mock::Mock::default();
+        let command = Command::new(0x0001, vec![]);
+        let stale = command.tag.wrapping_sub(1);
+
+        //a checksum_pages response left over from the command before
+        let mut report = vec![0x40 | 6];
+        report.extend_from_slice(&stale.to_le_bytes());
+        report.extend_from_slice(&[0x00, 0x00, 0x34, 0x12]);
+        mock.push_read(report);
+
+        assert!(matches!(
+            send(&command, &mock),
+            Err(Error::TagMismatch { expected, got }) if expected == command.tag && got == stale
+        ));
+    }
+
+    #[test]
+    fn send_doesnt_retry_unsupported() {
+        let mock = crate::mock::Mock::default().with_retries(2);
+        mock.push_failure(CommandResponseStatus::ParseError, 0);
+
+        assert!(send(&Command::new(0x0005, vec![]), &mock)
+            .unwrap_err()
+            .is_unsupported());
+        assert_eq!(mock.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn status_error_message() {
+        let e = Error::Status {
+            command: 0x0008,
+            status: CommandResponseStatus::ParseError,
+            status_info: 0,
+        };
+
+        assert_eq!(
+            e.to_string(),
+            "command 0x0008 failed: command not understood, status info 0"
+        );
+    }
+
+    #[test]
+    fn serial_between_response_packets() {
+        let mock = crate::mock::Mock::default();
+        mock.push_read(vec![0x06, 0x04, 0x00, 0x00, 0x00, 0x01, 0x02]);
+        mock.push_read(vec![0x80 | 3, b'h', b'i', b'\n']);
+        mock.push_read(vec![0xC0 | 2, b'!', b'\n']);
+        mock.push_read(vec![0x40 | 2, 0x03, 0x04]);
+
+        let rsp = rx(&mock, 4).unwrap();
+        assert_eq!(rsp.tag, 4);
+        assert_eq!(rsp.data, vec![0x01, 0x02, 0x03, 0x04]);
+
+        assert_eq!(
+            *mock.serial.borrow(),
+            vec![
+                (SerialStream::Stdout, b"hi\n".to_vec()),
+                (SerialStream::Stderr, b"!\n".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn report_size_32() {
+        let mock = crate::mock::Mock::with_report_size(32);
+
+        let data: Vec<u8> = (0..40).collect();
+        let command = Command {
+            tag: 4,
+            ..Command::new(0x0006, data.clone())
+        };
+        xmit(&command, &mock).unwrap();
+
+        //8 byte command header and 23 data bytes fill the first 31 byte payload
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].len(), 33);
+        assert_eq!(writes[0][1], 31);
+        assert_eq!(
+            &writes[0][2..10],
+            &[0x06, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(&writes[0][10..], &data[..23]);
+        assert_eq!(writes[1][1], 0x40 | 17);
+        assert_eq!(&writes[1][2..], &data[23..]);
+
+        //31 bytes, tag, status and 27 bytes of data, followed by the last 13
+        let mut first = vec![31, 0x04, 0x00, 0x00, 0x00];
+        first.extend_from_slice(&data[..27]);
+        let mut last = vec![0x40 | 13];
+        last.extend_from_slice(&data[27..]);
+        mock.push_read(first);
+        mock.push_read(last);
+
+        let rsp = rx(&mock, 4).unwrap();
+        assert_eq!(rsp.tag, 4);
+        assert_eq!(rsp.data, data);
+    }
+
+    #[test]
+    fn packet_over_short_reads() {
+        let mock = crate::mock::Mock::default();
+        mock.push_read(vec![0x40 | 7, 0x04, 0x00, 0x00]);
+        mock.push_read(vec![0x00, 1, 2, 3]);
+
+        let rsp = rx(&mock, 4).unwrap();
+        assert_eq!(rsp.tag, 4);
+        assert_eq!(rsp.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn packet_claiming_more_than_a_report() {
+        let mock = crate::mock::Mock::with_report_size(32);
+        mock.push_read(vec![0x40 | 40, 0x04, 0x00, 0x00, 0x00]);
+
+        assert!(matches!(
+            rx(&mock, 4),
+            Err(Error::ShortRead {
+                claimed: 40,
+                available: 31
+            })
+        ));
+    }
 }
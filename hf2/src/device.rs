@@ -0,0 +1,472 @@
+use crate::{
+    bin_info, reset_into_bootloader, BinInfoMode, Error, ReadWrite, SerialStream, MIN_REPORT_SIZE,
+    REPORT_SIZE,
+};
+use hidapi::{DeviceInfo, HidApi, HidDevice, HidError};
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+///Which way a raw report passed to an on_raw hook went.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    Write,
+    Read,
+}
+
+type RawHook = Box<dyn FnMut(Direction, &[u8]) + Send>;
+type SerialHook = Box<dyn FnMut(SerialStream, &[u8]) + Send>;
+
+///A HidDevice along with the options its hf2 framing uses, the report size for devices that don't use the 64 bytes the spec assumes, how long to wait for each packet, how often to resend a failed command and optional hooks seeing every raw report and the device's serial output.
+pub struct Device<D = HidDevice> {
+    hid: D,
+    report_size: usize,
+    timeout: Option<Duration>,
+    retries: u32,
+    on_raw: Option<RefCell<RawHook>>,
+    on_serial: Option<RefCell<SerialHook>>,
+}
+
+impl<D: ReadWrite> Device<D> {
+    pub fn new(hid: D) -> Self {
+        Device {
+            hid,
+            report_size: REPORT_SIZE,
+            timeout: None,
+            retries: 0,
+            on_raw: None,
+            on_serial: None,
+        }
+    }
+
+    ///hidapi 1.x doesn't expose the report descriptor, so report_size can only be checked against what the framing can express.
+    pub fn with_report_size(hid: D, report_size: usize) -> Result<Self, Error> {
+        if !(MIN_REPORT_SIZE..=REPORT_SIZE).contains(&report_size) {
+            return Err(Error::Arguments);
+        }
+
+        Ok(Device {
+            report_size,
+            ..Device::new(hid)
+        })
+    }
+
+    ///Give up with Error::Timeout when a packet of a response doesn't arrive within timeout. Applies per packet so long multi packet responses aren't cut short.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    ///Resend a command up to retries more times when its response times out or reports a failure.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    ///Call on_raw with every report written, including the leading report id, and every report read, for capturing or asserting on the exact bytes during bring up. Costs nothing when not set.
+    pub fn on_raw<F>(mut self, on_raw: F) -> Self
+    where
+        F: FnMut(Direction, &[u8]) + Send + 'static,
+    {
+        self.on_raw = Some(RefCell::new(Box::new(on_raw)));
+        self
+    }
+
+    ///Call on_serial with the stdout and stderr output the device sends in between command responses, instead of logging it.
+    pub fn on_serial<F>(mut self, on_serial: F) -> Self
+    where
+        F: FnMut(SerialStream, &[u8]) + Send + 'static,
+    {
+        self.on_serial = Some(RefCell::new(Box::new(on_serial)));
+        self
+    }
+
+    pub fn into_inner(self) -> D {
+        self.hid
+    }
+}
+
+impl<D> Deref for Device<D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        &self.hid
+    }
+}
+
+impl<D: ReadWrite> ReadWrite for Device<D> {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        if let Some(on_raw) = &self.on_raw {
+            (on_raw.borrow_mut())(Direction::Write, data);
+        }
+        self.hid.hf2_write(data)
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let count = self.hid.hf2_read(buf)?;
+        self.read_hook(&buf[..count]);
+        Ok(count)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let count = self.hid.hf2_read_timeout(buf, timeout)?;
+        self.read_hook(&buf[..count]);
+        Ok(count)
+    }
+    fn report_size(&self) -> usize {
+        self.report_size
+    }
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+    fn retries(&self) -> u32 {
+        self.retries
+    }
+    fn serial(&self, stream: SerialStream, data: &[u8]) {
+        match &self.on_serial {
+            Some(on_serial) => (on_serial.borrow_mut())(stream, data),
+            None => self.hid.serial(stream, data),
+        }
+    }
+}
+
+impl<D> Device<D> {
+    fn read_hook(&self, report: &[u8]) {
+        if let Some(on_raw) = &self.on_raw {
+            (on_raw.borrow_mut())(Direction::Read, report);
+        }
+    }
+}
+
+///Open device_info, returning Error::PermissionDenied when the os refused access rather than the device being gone.
+pub fn open_device(api: &HidApi, device_info: &DeviceInfo) -> Result<HidDevice, Error> {
+    device_info.open_device(api).map_err(|e| {
+        //grab errno before anything else gets a chance to overwrite it
+        let os_error = std::io::Error::last_os_error();
+
+        if is_permission_denied(&e, &os_error) {
+            Error::PermissionDenied
+        } else {
+            e.into()
+        }
+    })
+}
+
+///A bootloader looked for when no vid/pid is given.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct KnownDevice {
+    pub vid: u16,
+    pub pid: u16,
+    ///where apps start on the boards this bootloader ships on, None when that isn't known or they don't agree
+    pub app_address: Option<u32>,
+}
+
+const fn known(vid: u16, pid: u16, app_address: Option<u32>) -> KnownDevice {
+    KnownDevice {
+        vid,
+        pid,
+        app_address,
+    }
+}
+
+//apps start right after the uf2 bootloader, which takes 8k on samd21 and 16k on samd51
+const SAMD21: Option<u32> = Some(0x2000);
+const SAMD51: Option<u32> = Some(0x4000);
+
+const KNOWN_DEVICES: &[KnownDevice] = &[
+    known(0x1D50, 0x6110, None),
+    known(0x1D50, 0x6112, None),
+    known(0x239A, 0x0035, SAMD51),
+    known(0x239A, 0x002D, None),
+    known(0x239A, 0x0015, SAMD21),
+    known(0x239A, 0x001B, SAMD21),
+    known(0x239A, 0xB000, None),
+    known(0x239A, 0x0024, None),
+    known(0x239A, 0x000F, SAMD21),
+    known(0x239A, 0x0013, SAMD21),
+    known(0x239A, 0x0021, SAMD51),
+    known(0x239A, 0x0022, SAMD51),
+    known(0x239A, 0x0031, SAMD51),
+    known(0x239A, 0x002B, SAMD51),
+    known(0x239A, 0x0037, None),
+    known(0x239A, 0x002F, None),
+    known(0x239A, 0x0033, SAMD51),
+    known(0x239A, 0x0034, None),
+    known(0x239A, 0x003D, SAMD51),
+    known(0x239A, 0x0018, SAMD21),
+    known(0x239A, 0x001C, SAMD21),
+    known(0x239A, 0x001E, SAMD21),
+    known(0x239A, 0x0027, None),
+    known(0x04D8, 0xEDB3, None),
+    known(0x04D8, 0xEDBE, None),
+    known(0x04D8, 0xEF66, None),
+    known(0x2341, 0x024E, None),
+    known(0x2341, 0x8053, None),
+    known(0x2341, 0x024D, None),
+    known(0x16D0, 0x0CDA, None),
+    known(0x03EB, 0x2402, None),
+    known(0x2886, 0x000D, None),
+    known(0x2886, 0x002F, None),
+    known(0x1B4F, 0x0D23, None),
+    known(0x1B4F, 0x0D22, None),
+    known(0x1209, 0x4D44, None),
+    known(0x1209, 0x2017, None),
+];
+
+///The hf2 bootloaders looked for when no vid/pid is given.
+pub fn known_devices() -> &'static [KnownDevice] {
+    KNOWN_DEVICES
+}
+
+///Whether vid/pid is one of known_devices.
+pub fn is_known(vid: u16, pid: u16) -> bool {
+    known_device(vid, pid).is_some()
+}
+
+///The entry for vid/pid in known_devices.
+pub fn known_device(vid: u16, pid: u16) -> Option<&'static KnownDevice> {
+    known_devices()
+        .iter()
+        .find(|known| known.vid == vid && known.pid == pid)
+}
+
+///Enumerated devices with vid and pid when both are given, otherwise every known bootloader. With serial only the ones with that serial number.
+pub fn matching_devices<'a>(
+    api: &'a HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&'a str>,
+) -> impl Iterator<Item = &'a DeviceInfo> + 'a {
+    api.device_list()
+        .filter(move |device_info| match (vid, pid) {
+            (Some(v), Some(p)) => device_info.vendor_id() == v && device_info.product_id() == p,
+            _ => is_known(device_info.vendor_id(), device_info.product_id()),
+        })
+        .filter(move |device_info| serial.is_none() || device_info.serial_number() == serial)
+}
+
+///Open the first of candidates that opens, along with which one it was. If none do PermissionDenied is preferred over other errors since it's the one worth explaining, NotFound when there were no candidates.
+pub fn open_first<'a>(
+    api: &HidApi,
+    candidates: impl Iterator<Item = &'a DeviceInfo>,
+) -> Result<(HidDevice, &'a DeviceInfo), Error> {
+    let mut error = Error::NotFound;
+
+    for device_info in candidates {
+        match open_device(api, device_info) {
+            Ok(d) => return Ok((d, device_info)),
+            Err(e) if !matches!(error, Error::PermissionDenied) => error = e,
+            Err(_) => (),
+        }
+    }
+
+    Err(error)
+}
+
+///Open the first device with vid and pid that opens.
+pub fn open(api: &HidApi, vid: u16, pid: u16) -> Result<HidDevice, Error> {
+    open_first(api, matching_devices(api, Some(vid), Some(pid), None)).map(|(d, _)| d)
+}
+
+///Open the first known bootloader that opens.
+pub fn open_known_device(api: &HidApi) -> Result<HidDevice, Error> {
+    open_first(api, matching_devices(api, None, None, None)).map(|(d, _)| d)
+}
+
+///Open the known bootloader with this serial number.
+pub fn open_serial(api: &HidApi, serial: &str) -> Result<HidDevice, Error> {
+    open_first(api, matching_devices(api, None, None, Some(serial))).map(|(d, _)| d)
+}
+
+///How often wait_for_device refreshes the device list.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+///Wait until a device matching vid/pid and serial, as matching_devices has it, is enumerated, refreshing api's device list as it goes. Error::NotFound if none shows up within timeout. Nothing is opened.
+pub fn wait_for_device(
+    api: &mut HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if matching_devices(api, vid, pid, serial).next().is_some() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::NotFound);
+        }
+
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+        api.refresh_devices()?;
+    }
+}
+
+///How long wait_for_mode gives a device it just opened to answer bininfo.
+const MODE_POLL_TIMEOUT: Duration = Duration::from_millis(500);
+
+///Wait for a device matching vid/pid and serial, as matching_devices has it, to answer bininfo in mode, usually after a reset, refreshing api's device list as it goes. Until it drops off the bus it may still answer as it was, so only the mode it reports counts. The device comes back opened along with its path. Error::Timeout if none answers in mode within timeout. An app without an hf2 interface never answers, so this can't confirm a reset into one.
+pub fn wait_for_mode(
+    api: &mut HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    mode: BinInfoMode,
+    timeout: Duration,
+) -> Result<(HidDevice, CString), Error> {
+    let deadline = Instant::now() + timeout;
+
+    while Instant::now() < deadline {
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+        api.refresh_devices()?;
+
+        let candidates = matching_devices(api, vid, pid, serial);
+        if let Ok((hid, device_info)) = open_first(api, candidates) {
+            let path = device_info.path().to_owned();
+            let d = Device::new(hid).with_timeout(MODE_POLL_TIMEOUT);
+
+            if bin_info(&d).is_ok_and(|bininfo| bininfo.mode == mode) {
+                return Ok((d.into_inner(), path));
+            }
+        }
+    }
+
+    Err(Error::Timeout)
+}
+
+///Open the first device matching vid/pid and serial, as matching_devices has it, in bootloader mode whatever mode it's in now. One already in the bootloader comes back right away, one running its app is reset and reopened once it answers bininfo in the bootloader, found again by its serial number if it has one. Error::NoBootloader if it doesn't within timeout. The device comes back along with its path, like wait_for_mode.
+pub fn ensure_bootloader(
+    api: &mut HidApi,
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial: Option<&str>,
+    timeout: Duration,
+) -> Result<(HidDevice, CString), Error> {
+    let (hid, device_info) = open_first(api, matching_devices(api, vid, pid, serial))?;
+    let path = device_info.path().to_owned();
+    let serial = serial
+        .or(device_info.serial_number().filter(|s| !s.is_empty()))
+        .map(str::to_string);
+
+    let d = Device::new(hid).with_timeout(MODE_POLL_TIMEOUT);
+    if bin_info(&d)?.mode == BinInfoMode::Bootloader {
+        return Ok((d.into_inner(), path));
+    }
+
+    //usually no response, the device may well be gone before the write returns
+    if let Err(e) = reset_into_bootloader(&d) {
+        log::debug!("reset into bootloader {}", e);
+    }
+    drop(d);
+
+    match wait_for_mode(
+        api,
+        vid,
+        pid,
+        serial.as_deref(),
+        BinInfoMode::Bootloader,
+        timeout,
+    ) {
+        Err(Error::Timeout) => Err(Error::NoBootloader { timeout }),
+        result => result,
+    }
+}
+
+//hidapi only hands back a message, if any, so look at both that and errno
+fn is_permission_denied(e: &HidError, os_error: &std::io::Error) -> bool {
+    if let HidError::HidApiError { message } = e {
+        let message = message.to_lowercase();
+        if message.contains("permission") || message.contains("access denied") {
+            return true;
+        }
+    }
+
+    os_error.kind() == std::io::ErrorKind::PermissionDenied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn known_devices() {
+        assert!(is_known(0x239A, 0x003D));
+        assert!(is_known(0x1209, 0x2017));
+        assert!(!is_known(0x239A, 0x1234));
+        assert!(!is_known(0x1234, 0x003D));
+    }
+
+    #[test]
+    fn known_app_addresses() {
+        assert_eq!(
+            known_device(0x239A, 0x003D).unwrap().app_address,
+            Some(0x4000)
+        );
+        assert_eq!(
+            known_device(0x239A, 0x001B).unwrap().app_address,
+            Some(0x2000)
+        );
+        assert_eq!(known_device(0x1209, 0x2017).unwrap().app_address, None);
+    }
+
+    #[test]
+    fn known_devices_unique() {
+        let devices = KNOWN_DEVICES;
+        for (i, a) in devices.iter().enumerate() {
+            assert!(!devices[i + 1..]
+                .iter()
+                .any(|b| (a.vid, a.pid) == (b.vid, b.pid)));
+        }
+    }
+
+    #[test]
+    fn on_raw_sees_every_report() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let captured = seen.clone();
+
+        let mock = Mock::default();
+        mock.push_response(&[0x01, 0x00, 0x00, 0x00]);
+
+        let d = Device::new(mock).on_raw(move |direction, report| {
+            captured.lock().unwrap().push((direction, report.to_vec()))
+        });
+
+        let _ = crate::bin_info(&d);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].0, Direction::Write);
+        assert_eq!(seen[0].1, d.writes.borrow()[0]);
+        assert_eq!(seen[1].0, Direction::Read);
+        let tag = d.tag();
+        assert_eq!(
+            seen[1].1,
+            vec![0x48, tag[0], tag[1], 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn timeout_when_no_packet_arrives() {
+        let d = Device::new(Mock::default()).with_timeout(Duration::from_millis(10));
+
+        assert!(matches!(crate::bin_info(&d), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn permission_denied() {
+        let busy = std::io::Error::from(std::io::ErrorKind::Other);
+        let denied = HidError::HidApiError {
+            message: "open: Permission denied".into(),
+        };
+        assert!(is_permission_denied(&denied, &busy));
+
+        let unknown = HidError::HidApiError { message: "".into() };
+        let eacces = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(is_permission_denied(&unknown, &eacces));
+
+        assert!(!is_permission_denied(&unknown, &busy));
+    }
+}
@@ -1,25 +1,30 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, strip_length_header, Command};
+use crate::{Error, ReadWrite};
+use alloc::{string::String, string::ToString, vec, vec::Vec};
 use scroll::{ctx, Pread, LE};
 
-///Return internal log buffer if any. The result is a character array.
+///Return internal log buffer if any. The result is a character array. Invalid utf8 is replaced with U+FFFD rather than losing the whole log to a stray byte.
+pub fn dmesg(d: &impl ReadWrite) -> Result<DmesgResponse, Error> {
+    let data = dmesg_raw(d)?;
 
-pub fn dmesg(d: &hidapi::HidDevice) -> Result<DmesgResponse, Error> {
-    xmit(Command::new(0x0010, 0, vec![]), d)?;
+    (data.as_slice()).pread_with(0, LE)
+}
 
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => return (data.as_slice()).pread_with(0, LE),
-        Ok(_) => return Err(Error::CommandNotRecognized),
-        Err(e) => return Err(e),
-    }
+///Same as dmesg, but fails with Error::Parse if the log isn't entirely valid utf8.
+pub fn dmesg_strict(d: &impl ReadWrite) -> Result<DmesgResponse, Error> {
+    let data = dmesg_raw(d)?;
+    let logs = core::str::from_utf8(strip_length_header(&data))?;
+
+    Ok(DmesgResponse { logs: logs.into() })
+}
+
+fn dmesg_raw(d: &impl ReadWrite) -> Result<Vec<u8>, Error> {
+    send(&Command::new(0x0010, vec![]), d).map(|rsp| rsp.data)
 }
 
 ///Response to the dmesg command
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DmesgResponse {
     pub logs: String,
 }
@@ -27,13 +32,112 @@ pub struct DmesgResponse {
 impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for DmesgResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
-        let mut bytes = vec![0; this.len()];
+        let header = this.len() - strip_length_header(this).len();
+        let mut bytes = vec![0; this.len() - header];
 
-        let mut offset = 0;
+        let mut offset = header;
         this.gread_inout_with(&mut offset, &mut bytes, le)?;
 
-        let logs = core::str::from_utf8(&bytes)?;
+        let logs = String::from_utf8_lossy(&bytes);
+
+        Ok((
+            DmesgResponse {
+                logs: logs.into_owned(),
+            },
+            offset,
+        ))
+    }
+}
+
+///Remembers the log between dmesg polls to hand back only what was appended, like tail -f.
+#[derive(Debug, Default)]
+pub struct DmesgFollower {
+    previous: String,
+}
+
+impl DmesgFollower {
+    ///Poll dmesg once, returning what was appended since the last poll.
+    pub fn poll(&mut self, d: &impl ReadWrite) -> Result<String, Error> {
+        Ok(self.update(dmesg(d)?.logs))
+    }
+
+    ///What logs has on top of the previous ones. The whole of logs when it no longer starts with them, the device's buffer wrapped or was reset in between.
+    pub fn update(&mut self, logs: String) -> String {
+        let delta = match logs.strip_prefix(self.previous.as_str()) {
+            Some(appended) => appended.to_string(),
+            None => logs.clone(),
+        };
+
+        self.previous = logs;
+        delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_response() {
+        let data = b"boot ok\nflash ready\n".to_vec();
+
+        let res: DmesgResponse = (data.as_slice())
+            .pread_with::<DmesgResponse>(0, LE)
+            .unwrap();
+
+        assert_eq!(res.logs, "boot ok\nflash ready\n");
+    }
+
+    #[test]
+    fn parse_response_with_length_header() {
+        let mut data: Vec<u8> = vec![0x14, 0x00];
+        data.extend_from_slice(b"boot ok\nflash ready\n");
+
+        let res: DmesgResponse = (data.as_slice())
+            .pread_with::<DmesgResponse>(0, LE)
+            .unwrap();
+
+        assert_eq!(res.logs, "boot ok\nflash ready\n");
+    }
+
+    #[test]
+    fn parse_response_with_invalid_utf8() {
+        let mut data = b"boot ok\n".to_vec();
+        data.push(0xFF);
+        data.extend_from_slice(b"flash ready\n");
+
+        let res: DmesgResponse = (data.as_slice())
+            .pread_with::<DmesgResponse>(0, LE)
+            .unwrap();
+
+        assert_eq!(res.logs, "boot ok\n\u{FFFD}flash ready\n");
+    }
+
+    #[test]
+    fn strict_rejects_invalid_utf8() {
+        let mock = crate::mock::Mock::default();
+        mock.push_response(&[b'o', b'k', 0xFF]);
+
+        assert!(dmesg_strict(&mock).is_err());
+
+        mock.push_response(b"ok");
+        assert_eq!(dmesg_strict(&mock).unwrap().logs, "ok");
+    }
+
+    #[test]
+    fn follow_yields_appended() {
+        let mut follower = DmesgFollower::default();
+
+        assert_eq!(follower.update("boot ok\n".into()), "boot ok\n");
+        assert_eq!(follower.update("boot ok\n".into()), "");
+        assert_eq!(
+            follower.update("boot ok\nflash ready\n".into()),
+            "flash ready\n"
+        );
 
-        Ok((DmesgResponse { logs: logs.into() }, offset))
+        //buffer reset, start over
+        assert_eq!(follower.update("boot ok\n".into()), "boot ok\n");
+        //wrapped, same length but shifted
+        assert_eq!(follower.update("ot ok\nhi\n".into()), "ot ok\nhi\n");
     }
 }
@@ -0,0 +1,68 @@
+use crate::{bin_info, write_flash_page, Error, ReadWrite};
+use alloc::vec;
+
+///Overwrite length bytes of flash from address with zeros, rounded up to whole pages, returning how many pages were written. A logical erase done with write_flash_page, not a hardware sector erase, so the pages read back as 0x00 rather than the erased 0xFF. address has to be on a page boundary and the whole range within flash, otherwise nothing is written.
+pub fn erase_region(d: &impl ReadWrite, address: u32, length: u32) -> Result<u32, Error> {
+    let bininfo = bin_info(d)?;
+    let page_size = bininfo.flash_page_size;
+
+    if !address.is_multiple_of(page_size) {
+        return Err(Error::UnalignedAddress { address, page_size });
+    }
+    bininfo.check_fits(address, length as usize)?;
+
+    let zeros = vec![0_u8; page_size as usize];
+    let num_pages = length.div_ceil(page_size);
+
+    for page_index in 0..num_pages {
+        write_flash_page(d, address + page_index * page_size, &zeros)?;
+    }
+    Ok(num_pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+
+    ///bootloader mode with 1024 pages of 16 bytes
+    fn bininfo(mock: &Mock) {
+        let mut bininfo = vec![];
+        for word in &[1_u32, 16, 1024, 64] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+    }
+
+    #[test]
+    fn writes_zero_pages() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        mock.push_response(&[]);
+        mock.push_response(&[]);
+
+        assert_eq!(erase_region(&mock, 0x2000, 20).unwrap(), 2);
+
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(&writes[2][10..14], &[0x10, 0x20, 0x00, 0x00]);
+        assert!(writes[2][14..30].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn refuses_unaligned_or_past_flash() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        assert!(matches!(
+            erase_region(&mock, 0x2004, 16),
+            Err(Error::UnalignedAddress { .. })
+        ));
+
+        bininfo(&mock);
+        assert!(matches!(
+            erase_region(&mock, 0x3FF0, 32),
+            Err(Error::ImageTooLarge { .. })
+        ));
+        assert_eq!(mock.writes.borrow().len(), 2);
+    }
+}
@@ -0,0 +1,136 @@
+use alloc::string::String;
+use core::fmt;
+use core::ops::AddAssign;
+use core::time::Duration;
+
+///Progress and result of a flash, in the order they happen. Page indexes count from the first page of the image.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", rename_all = "snake_case"))]
+pub enum FlashEvent {
+    ///image padded to total_pages pages, about to start
+    Started { total_pages: u32 },
+    ///device checksums received for count pages starting at address from
+    ChecksumRead { from: u32, count: u32 },
+    ///page already matched the device checksum and was not written
+    PageSkipped { index: u32 },
+    ///page written at address
+    PageWritten { index: u32, address: u32 },
+    ///flash finished successfully
+    Done { stats: FlashStats },
+    ///flash aborted
+    Failed { error: String },
+}
+
+///Outcome of a successful flash.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FlashStats {
+    ///pages that differed from the device and were written
+    pub pages_written: u32,
+    ///pages whose device checksum already matched
+    pub pages_skipped: u32,
+    ///extra writes of pages that didn't match their checksum right after writing
+    pub page_retries: u32,
+    pub flash_page_size: u32,
+    ///time spent writing pages
+    pub elapsed: Duration,
+    ///wall clock time of the whole flash, checksums included
+    pub wall_time: Duration,
+}
+
+impl FlashStats {
+    pub fn total_pages(&self) -> u32 {
+        self.pages_written + self.pages_skipped
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        u64::from(self.pages_written) * u64::from(self.flash_page_size)
+    }
+
+    pub fn bytes_skipped(&self) -> u64 {
+        u64::from(self.pages_skipped) * u64::from(self.flash_page_size)
+    }
+
+    ///Bytes written per second in KB, None when nothing took measurable time.
+    pub fn kb_per_sec(&self) -> Option<f64> {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            Some(self.bytes_written() as f64 / 1024.0 / secs)
+        } else {
+            None
+        }
+    }
+}
+
+///Totals of several segments flashed one after another, they all share the same flash so the page size is taken as is.
+impl AddAssign for FlashStats {
+    fn add_assign(&mut self, other: Self) {
+        self.pages_written += other.pages_written;
+        self.pages_skipped += other.pages_skipped;
+        self.page_retries += other.page_retries;
+        self.flash_page_size = other.flash_page_size;
+        self.elapsed += other.elapsed;
+        self.wall_time += other.wall_time;
+    }
+}
+
+impl fmt::Display for FlashStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+
+        write!(
+            f,
+            "Flashed {}/{} pages ({} KB skipped), {} KB in {:.1}s",
+            self.pages_written,
+            self.total_pages(),
+            self.bytes_skipped() / 1024,
+            self.bytes_written() / 1024,
+            secs
+        )?;
+
+        if let Some(kb_per_sec) = self.kb_per_sec() {
+            write!(f, " ({:.0} KB/s)", kb_per_sec)?;
+        }
+        if !self.wall_time.is_zero() {
+            write!(f, ", {:.1}s total", self.wall_time.as_secs_f64())?;
+        }
+        if self.page_retries > 0 {
+            write!(f, ", {} page retries", self.page_retries)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn display_stats() {
+        let stats = FlashStats {
+            pages_written: 128,
+            pages_skipped: 384,
+            page_retries: 0,
+            flash_page_size: 512,
+            elapsed: Duration::from_millis(1250),
+            wall_time: Duration::ZERO,
+        };
+
+        assert_eq!(
+            stats.to_string(),
+            "Flashed 128/512 pages (192 KB skipped), 64 KB in 1.2s (51 KB/s)"
+        );
+
+        let stats = FlashStats {
+            wall_time: Duration::from_millis(2000),
+            ..stats
+        };
+
+        assert_eq!(
+            stats.to_string(),
+            "Flashed 128/512 pages (192 KB skipped), 64 KB in 1.2s (51 KB/s), 2.0s total"
+        );
+    }
+}
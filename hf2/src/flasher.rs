@@ -0,0 +1,1032 @@
+use crate::checksumpages::max_checksum_pages;
+use crate::{
+    bin_info, changed_pages, changed_pages_with_progress, changed_runs, checksum_pages,
+    max_write_pages, page_checksums, page_run, start_flash_if_supported, write_flash_page,
+    written_mismatches, BinInfoMode, BinInfoResponse, ChecksumRegionIter, Error, FlashEvent,
+    FlashStats, Mismatch, PageWriter, ReadWrite, SerialStream,
+};
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+///How a Flasher talks to its device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlasherConfig {
+    ///give up with Error::Timeout when a packet of a response doesn't arrive within this, None keeps the device's own
+    pub timeout: Option<Duration>,
+    ///how many more times a command is sent when its response times out or reports a failure
+    pub retries: u32,
+    ///write as many consecutive pages per message as max_message_size allows, falling back to a page at a time if the device rejects it
+    pub batch_pages: bool,
+    ///checksum each page right after it's written and rewrite it up to PAGE_RETRIES times when it didn't stick, Error::Unverified when it still doesn't match
+    pub verify_each: bool,
+    ///checksum every written page again once the writes are done, Error::Unverified when any of them didn't commit
+    pub verify_after: bool,
+    ///how many times a flash tries reopening the device with the hook given to with_reopen once its handle goes stale, before giving up with Error::ReopenFailed
+    pub reopen_attempts: u32,
+}
+
+impl Default for FlasherConfig {
+    fn default() -> Self {
+        FlasherConfig {
+            timeout: None,
+            retries: 3,
+            batch_pages: false,
+            verify_each: false,
+            verify_after: false,
+            reopen_attempts: 3,
+        }
+    }
+}
+
+type Reopen<'a, D> = Box<dyn FnMut() -> Result<D, Error> + 'a>;
+
+///How many times verify_each rewrites a page whose checksum doesn't match after writing.
+pub const PAGE_RETRIES: u32 = 3;
+
+///How long a flash waits before each attempt at reopening a stale device, giving it time to enumerate again.
+const REOPEN_INTERVAL: Duration = Duration::from_millis(500);
+
+///Flashing and verifying images against a single device. Queries bininfo once, switching the device into the bootloader if it isn't already.
+pub struct Flasher<'a, D: ReadWrite> {
+    d: Configured<'a, D>,
+    bininfo: BinInfoResponse,
+    writer: PageWriter,
+    events: Option<Sender<FlashEvent>>,
+    reopen: Option<Reopen<'a, D>>,
+}
+
+impl<'a, D: ReadWrite> Flasher<'a, D> {
+    pub fn new(d: &'a D) -> Result<Self, Error> {
+        Self::with_config(d, FlasherConfig::default())
+    }
+
+    ///Same as new, giving up with Error::Timeout when a packet of any response doesn't arrive within timeout, whatever d's own timeout.
+    pub fn with_timeout(d: &'a D, timeout: Duration) -> Result<Self, Error> {
+        Self::with_config(
+            d,
+            FlasherConfig {
+                timeout: Some(timeout),
+                ..FlasherConfig::default()
+            },
+        )
+    }
+
+    pub fn with_config(d: &'a D, config: FlasherConfig) -> Result<Self, Error> {
+        let d = Configured {
+            d: RefCell::new(Handle::Borrowed(d)),
+            config,
+            serial: RefCell::default(),
+        };
+        let bininfo = bootloader_bininfo(&d)?;
+        let writer = page_writer(&bininfo, d.config.batch_pages);
+
+        Ok(Flasher {
+            d,
+            bininfo,
+            writer,
+            events: None,
+            reopen: None,
+        })
+    }
+
+    ///Also send every FlashEvent of a flash to events as it happens, for a GUI to render. Nothing stops when the receiver goes away.
+    pub fn with_events(mut self, events: Sender<FlashEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    ///When the device's handle goes stale part way through a flash, as it can when the device briefly drops off the bus, get a new one from reopen and carry on from the pages that were being checksummed or written, up to reopen_attempts times. reopen is whatever opened the device in the first place, by the same vid/pid or serial number. The reopened device is used for everything after.
+    pub fn with_reopen(mut self, reopen: impl FnMut() -> Result<D, Error> + 'a) -> Self {
+        self.reopen = Some(Box::new(reopen));
+        self
+    }
+
+    ///The device reopen handed the flasher once the one it was made with went stale, None if it never had to. The one it was made with is dead then, whatever comes after the flash wants this one.
+    pub fn into_reopened(self) -> Option<D> {
+        match self.d.d.into_inner() {
+            Handle::Borrowed(_) => None,
+            Handle::Owned(d) => Some(d),
+        }
+    }
+
+    ///Serial output the device sent while the flasher was talking to it, oldest first, since the last call.
+    pub fn take_serial(&mut self) -> Vec<(SerialStream, Vec<u8>)> {
+        self.d.serial.take()
+    }
+
+    ///bininfo as read when the flasher was created, or last refreshed.
+    pub fn bininfo(&self) -> &BinInfoResponse {
+        &self.bininfo
+    }
+
+    ///Read bininfo again, switching into the bootloader if needed, for when the device may have changed since, like after reset_into_bootloader on a handle that survived it. Only the flasher's own commands use the cached bininfo, nothing else does.
+    pub fn refresh(&mut self) -> Result<&BinInfoResponse, Error> {
+        self.bininfo = bootloader_bininfo(&self.d)?;
+        self.writer = page_writer(&self.bininfo, self.d.config.batch_pages);
+        Ok(&self.bininfo)
+    }
+
+    ///Write data at base, padded to whole pages. Unless skip_checksum, only pages whose device checksum differs are written. Nothing is written when base isn't on a page boundary or it wouldn't fit in flash.
+    pub fn flash(
+        &mut self,
+        base: u32,
+        data: &[u8],
+        skip_checksum: bool,
+    ) -> Result<FlashStats, Error> {
+        self.flash_with_progress(base, data, skip_checksum, |_, _| {})
+    }
+
+    ///Same as flash, calling progress with the number of pages written so far and the number that need writing after every message.
+    pub fn flash_with_progress<F>(
+        &mut self,
+        base: u32,
+        data: &[u8],
+        skip_checksum: bool,
+        progress: F,
+    ) -> Result<FlashStats, Error>
+    where
+        F: FnMut(u32, u32),
+    {
+        let result = self.flash_pages(base, data, skip_checksum, progress);
+
+        match &result {
+            Ok(stats) => self.emit(FlashEvent::Done {
+                stats: stats.clone(),
+            }),
+            Err(e) => self.emit(FlashEvent::Failed {
+                error: e.to_string(),
+            }),
+        }
+        result
+    }
+
+    fn flash_pages<F>(
+        &mut self,
+        base: u32,
+        data: &[u8],
+        skip_checksum: bool,
+        mut progress: F,
+    ) -> Result<FlashStats, Error>
+    where
+        F: FnMut(u32, u32),
+    {
+        self.bininfo.check_aligned(base)?;
+        self.bininfo.check_fits(base, data.len())?;
+
+        let started = Instant::now();
+        let pages: Vec<_> = self.bininfo.pages(data, base).collect();
+        let num_pages = pages.len();
+        log::debug!("binary is {} bytes, {} pages", data.len(), num_pages);
+        self.emit(FlashEvent::Started {
+            total_pages: num_pages as u32,
+        });
+
+        let messages = self.writer.messages();
+        let mut elapsed = Duration::default();
+        let mut pages_written = 0;
+        let mut page_retries = 0;
+        let mut written = vec![false; num_pages];
+        let file_crcs = if self.d.config.verify_each {
+            page_checksums(data, self.bininfo.flash_page_size)
+        } else {
+            vec![]
+        };
+
+        let changed: Vec<bool> = if skip_checksum {
+            vec![true; num_pages]
+        } else {
+            self.resumable(|flasher| {
+                let events = &flasher.events;
+                changed_pages_with_progress(
+                    &flasher.d,
+                    &flasher.bininfo,
+                    base,
+                    data,
+                    |from, count| emit(events, FlashEvent::ChecksumRead { from, count }),
+                )
+            })?
+        };
+        let to_write = changed.iter().filter(|changed| **changed).count() as u32;
+
+        for (index, _) in changed.iter().enumerate().filter(|(_, changed)| !**changed) {
+            self.emit(FlashEvent::PageSkipped {
+                index: index as u32,
+            });
+        }
+
+        for run in changed_runs(&changed, self.writer.max_pages()) {
+            let target_address = pages[run.start].0;
+            let run_data = page_run(data, self.bininfo.flash_page_size, run.clone());
+
+            let instant = Instant::now();
+            self.resumable(|flasher| flasher.writer.write(&flasher.d, target_address, &run_data))?;
+            if self.d.config.verify_each {
+                for index in run.clone() {
+                    let (address, page) = &pages[index];
+                    page_retries += self.resumable(|flasher| {
+                        verify_page(&flasher.d, index as u32, *address, page, file_crcs[index])
+                    })?;
+                }
+            }
+            elapsed += instant.elapsed();
+
+            pages_written += run.len() as u32;
+            for index in run {
+                written[index] = true;
+                self.emit(FlashEvent::PageWritten {
+                    index: index as u32,
+                    address: pages[index].0,
+                });
+            }
+            progress(pages_written, to_write);
+        }
+
+        if self.d.config.verify_after {
+            let mismatches = self.resumable(|flasher| {
+                written_mismatches(&flasher.d, &flasher.bininfo, base, data, &written)
+            })?;
+            if !mismatches.is_empty() {
+                return Err(Error::Unverified { mismatches });
+            }
+        }
+
+        let stats = FlashStats {
+            pages_written,
+            pages_skipped: num_pages as u32 - pages_written,
+            page_retries,
+            flash_page_size: self.bininfo.flash_page_size,
+            elapsed,
+            wall_time: started.elapsed(),
+        };
+        log::info!(
+            "{} pages in {} messages, {:.1} KB/s, {:.1}s total",
+            pages_written,
+            self.writer.messages() - messages,
+            stats.kb_per_sec().unwrap_or_default(),
+            stats.wall_time.as_secs_f64()
+        );
+
+        Ok(stats)
+    }
+
+    ///Flash each of segments, (address, data) such as parse_uf2 and parse_ihex return, padded to whole pages on its own, with the stats totalled. Nothing is written unless every segment starts on a page boundary, fits in flash and shares no page with another.
+    pub fn flash_segments(
+        &mut self,
+        segments: &[(u32, Vec<u8>)],
+        skip_checksum: bool,
+    ) -> Result<FlashStats, Error> {
+        self.bininfo.check_segments(segments)?;
+
+        let mut stats = FlashStats::default();
+        for (address, data) in segments {
+            stats += self.flash(*address, data, skip_checksum)?;
+        }
+        Ok(stats)
+    }
+
+    ///Whether each page of data at base differs from the device, the pages flash writes unless skip_checksum. Nothing is written, for a dry run.
+    pub fn changed_pages(&self, base: u32, data: &[u8]) -> Result<Vec<bool>, Error> {
+        self.bininfo.check_aligned(base)?;
+        changed_pages(&self.d, &self.bininfo, base, data)
+    }
+
+    ///Compare data at base, padded to whole pages, against the device checksums. Every differing page is returned, empty when they all match.
+    pub fn verify(&mut self, base: u32, data: &[u8]) -> Result<Vec<Mismatch>, Error> {
+        self.bininfo.check_aligned(base)?;
+        let num_pages = self.bininfo.pages(data, base).len() as u32;
+        let mut mismatches = vec![];
+
+        let page_size = self.bininfo.flash_page_size;
+        let file_crcs = page_checksums(data, page_size);
+        let device_crcs = self
+            .checksums(base, num_pages)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        //zip would quietly stop at the shorter one, leaving the rest of the image unchecked
+        if device_crcs.len() != file_crcs.len() {
+            return Err(Error::Parse);
+        }
+
+        for (page_index, (file_crc, device_crc)) in
+            file_crcs.into_iter().zip(device_crcs).enumerate()
+        {
+            if device_crc != file_crc {
+                mismatches.push(Mismatch {
+                    page_index: page_index as u32,
+                    address: base + page_index as u32 * page_size,
+                    device_crc,
+                    file_crc,
+                });
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    fn emit(&self, event: FlashEvent) {
+        emit(&self.events, event)
+    }
+
+    ///Run step, and when it fails because the device's handle went stale reopen the device and run it again, all of it since pages are written whole. Error::ReopenFailed with the last error once reopen_attempts are used up.
+    fn resumable<T>(
+        &mut self,
+        mut step: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut attempts = 0;
+
+        loop {
+            let mut last = match step(self) {
+                Err(e) if self.reopen.is_some() && is_stale(&e) => e,
+                result => return result,
+            };
+
+            loop {
+                if attempts == self.d.config.reopen_attempts {
+                    return Err(Error::ReopenFailed {
+                        attempts,
+                        last: Box::new(last),
+                    });
+                }
+                attempts += 1;
+                log::warn!(
+                    "device handle went stale: {}, reopening {} of {}",
+                    last,
+                    attempts,
+                    self.d.config.reopen_attempts
+                );
+
+                std::thread::sleep(REOPEN_INTERVAL);
+                match self.reopen_device() {
+                    Ok(()) => break,
+                    Err(e) => last = e,
+                }
+            }
+        }
+    }
+
+    fn reopen_device(&mut self) -> Result<(), Error> {
+        let reopen = self.reopen.as_mut().ok_or(Error::Arguments)?;
+        let d = reopen()?;
+        *self.d.d.borrow_mut() = Handle::Owned(d);
+
+        //it may have come back in its app, and a device answering bininfo is one worth carrying on with
+        bootloader_bininfo(&self.d)?;
+        Ok(())
+    }
+
+    fn checksums(
+        &self,
+        base: u32,
+        num_pages: u32,
+    ) -> Result<ChecksumRegionIter<'_, Configured<'a, D>>, Error> {
+        Ok(ChecksumRegionIter::new(
+            &self.d,
+            base,
+            num_pages,
+            self.bininfo.flash_page_size,
+            max_checksum_pages(&self.bininfo)?,
+        ))
+    }
+}
+
+///bininfo once the device is in the bootloader, starting it if needed.
+fn bootloader_bininfo(d: &impl ReadWrite) -> Result<BinInfoResponse, Error> {
+    let bininfo = bin_info(d)?;
+    log::debug!("{:?}", bininfo);
+
+    if bininfo.mode == BinInfoMode::Bootloader {
+        return Ok(bininfo);
+    }
+    start_flash_if_supported(d)?;
+
+    //what mode it's in now, rather than the app mode it was asked about in
+    let bininfo = bin_info(d)?;
+    log::debug!("{:?}", bininfo);
+    Ok(bininfo)
+}
+
+///Checksum the page just written at address, page_index of the image, and rewrite it until it matches file_crc, up to PAGE_RETRIES times. How many rewrites it took.
+fn verify_page(
+    d: &impl ReadWrite,
+    page_index: u32,
+    address: u32,
+    page: &[u8],
+    file_crc: u16,
+) -> Result<u32, Error> {
+    let mut retries = 0;
+
+    loop {
+        let device_crc = *checksum_pages(d, address, 1)?
+            .checksums
+            .first()
+            .ok_or(Error::Parse)?;
+        if device_crc == file_crc {
+            return Ok(retries);
+        }
+        if retries == PAGE_RETRIES {
+            return Err(Error::Unverified {
+                mismatches: vec![Mismatch {
+                    page_index,
+                    address,
+                    device_crc,
+                    file_crc,
+                }],
+            });
+        }
+
+        log::debug!("page {} didn't stick, rewriting", page_index);
+        write_flash_page(d, address, page)?;
+        retries += 1;
+    }
+}
+
+fn page_writer(bininfo: &BinInfoResponse, batch_pages: bool) -> PageWriter {
+    let max_pages = if batch_pages {
+        max_write_pages(bininfo)
+    } else {
+        1
+    };
+    PageWriter::new(bininfo.flash_page_size, max_pages)
+}
+
+///Whether e is the transport failing outright, as a handle to a device that's no longer there does, rather than the device answering badly or not at all.
+fn is_stale(e: &Error) -> bool {
+    matches!(e, Error::Transmission(_) | Error::Io(_))
+}
+
+fn emit(events: &Option<Sender<FlashEvent>>, event: FlashEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
+
+///The device a Flasher was made with, or the one it reopened since.
+enum Handle<'a, D> {
+    Borrowed(&'a D),
+    Owned(D),
+}
+
+impl<'a, D> Deref for Handle<'a, D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        match self {
+            Handle::Borrowed(d) => d,
+            Handle::Owned(d) => d,
+        }
+    }
+}
+
+///The flasher's device with its config taking the place of the device's own timeout and retries, queuing serial output for take_serial.
+struct Configured<'a, D> {
+    d: RefCell<Handle<'a, D>>,
+    config: FlasherConfig,
+    serial: RefCell<Vec<(SerialStream, Vec<u8>)>>,
+}
+
+impl<'a, D: ReadWrite> ReadWrite for Configured<'a, D> {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        self.d.borrow().hf2_write(data)
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.d.borrow().hf2_read(buf)
+    }
+    fn report_size(&self) -> usize {
+        self.d.borrow().report_size()
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.d.borrow().hf2_read_timeout(buf, timeout)
+    }
+    fn timeout(&self) -> Option<Duration> {
+        self.config.timeout.or_else(|| self.d.borrow().timeout())
+    }
+    fn retries(&self) -> u32 {
+        self.config.retries
+    }
+    fn serial(&self, stream: SerialStream, data: &[u8]) {
+        self.serial.borrow_mut().push((stream, data.to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+    use crate::{crc16, CommandResponseStatus};
+
+    ///bootloader mode with 1024 pages of 16 bytes
+    fn bininfo(mock: &Mock) {
+        let mut bininfo = vec![];
+        for word in &[1_u32, 16, 1024, 64] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+    }
+
+    #[test]
+    fn flash_writes_changed_pages() {
+        let mock = Mock::default();
+        bininfo(&mock);
+
+        let data = [0xAA_u8; 32];
+        let first = crc16(&data[..16]);
+        let mut checksums = first.to_le_bytes().to_vec();
+        checksums.extend_from_slice(&0_u16.to_le_bytes());
+        mock.push_response(&checksums);
+        mock.push_response(&[]);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+        let stats = flasher.flash(0x2000, &data, false).unwrap();
+
+        assert_eq!(stats.pages_written, 1);
+        assert_eq!(stats.pages_skipped, 1);
+        assert!(flasher.into_reopened().is_none());
+
+        //bininfo, checksums, then a single page write at the second page
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(&writes[2][10..14], &0x2010_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn flash_without_start_flash() {
+        let mock = Mock::default();
+        //user mode, then a bootloader that doesn't know START_FLASH
+        let mut bininfo = vec![];
+        for word in &[2_u32, 16, 1024, 64] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+        mock.push_failure(CommandResponseStatus::ParseError, 0);
+        mock.push_response(&bininfo);
+        mock.push_response(&[]);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+        let stats = flasher.flash(0x2000, &[0xAA; 16], true).unwrap();
+
+        assert_eq!(stats.pages_written, 1);
+        assert_eq!(mock.writes.borrow().len(), 4);
+    }
+
+    #[test]
+    fn start_flash_rereads_bininfo() {
+        let mock = Mock::default();
+        let mut app = vec![];
+        for word in &[2_u32, 16, 1024, 64] {
+            app.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&app);
+        mock.push_response(&[]);
+        bininfo(&mock);
+
+        let flasher = Flasher::new(&mock).unwrap();
+
+        assert_eq!(flasher.bininfo().mode, BinInfoMode::Bootloader);
+        assert_eq!(mock.writes.borrow().len(), 3);
+    }
+
+    #[test]
+    fn progress_on_every_write() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        mock.push_response(&[]);
+        mock.push_response(&[]);
+
+        let mut calls = vec![];
+        let mut flasher = Flasher::new(&mock).unwrap();
+        flasher
+            .flash_with_progress(0x2000, &[0xAA; 20], true, |written, total| {
+                calls.push((written, total))
+            })
+            .unwrap();
+
+        assert_eq!(calls, vec![(1, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn verify_reports_mismatches() {
+        let mock = Mock::default();
+        bininfo(&mock);
+
+        let data = [0x55_u8; 48];
+        let page = crc16(&data[..16]);
+        let mut checksums = vec![];
+        for checksum in &[page, 0x1234, page] {
+            checksums.extend_from_slice(&checksum.to_le_bytes());
+        }
+        mock.push_response(&checksums);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+
+        assert_eq!(
+            flasher.verify(0x2000, &data).unwrap(),
+            vec![Mismatch {
+                page_index: 1,
+                address: 0x2010,
+                device_crc: 0x1234,
+                file_crc: page,
+            }]
+        );
+    }
+
+    #[test]
+    fn timeout_while_flashing() {
+        let mock = Mock::default();
+        bininfo(&mock);
+
+        let mut flasher = Flasher::with_timeout(&mock, Duration::from_millis(10)).unwrap();
+
+        //no checksum response queued
+        assert!(matches!(
+            flasher.flash(0x2000, &[0xAA; 16], false),
+            Err(Error::Timeout)
+        ));
+    }
+
+    #[test]
+    fn rewrites_page_after_failed_write() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        mock.push_failure(CommandResponseStatus::ExecutionError, 0);
+        mock.push_response(&[]);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+        let stats = flasher.flash(0x2000, &[0xAA; 16], true).unwrap();
+
+        assert_eq!(stats.pages_written, 1);
+
+        //bininfo, then the same page twice
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[1], writes[2]);
+    }
+
+    #[test]
+    fn queues_serial_output() {
+        let mock = Mock::default();
+        mock.push_read(vec![0x80 | 6, b'h', b'e', b'l', b'l', b'o', b'\n']);
+        bininfo(&mock);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+
+        assert_eq!(
+            flasher.take_serial(),
+            vec![(SerialStream::Stdout, b"hello\n".to_vec())]
+        );
+        assert!(flasher.take_serial().is_empty());
+    }
+
+    #[test]
+    fn rejects_image_past_flash() {
+        let mock = Mock::default();
+        bininfo(&mock);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+
+        assert!(matches!(
+            flasher.flash(0x3FF0, &[0xAA; 17], true),
+            Err(Error::ImageTooLarge { .. })
+        ));
+        assert_eq!(mock.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn batches_consecutive_pages() {
+        let mock = Mock::default();
+        //room for 4 pages of 8 bytes per message, still a single report
+        let mut info = vec![];
+        for word in &[1_u32, 8, 1024, 44] {
+            info.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&info);
+        mock.push_response(&[]);
+        mock.push_response(&[]);
+
+        let config = FlasherConfig {
+            batch_pages: true,
+            ..Default::default()
+        };
+        let mut flasher = Flasher::with_config(&mock, config).unwrap();
+        let stats = flasher.flash(0x1000, &[0xAA; 40], true).unwrap();
+
+        assert_eq!(stats.pages_written, 5);
+
+        //bininfo, 4 pages, then the last one
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[1].len(), 14 + 32);
+        assert_eq!(&writes[2][10..14], &0x1020_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn verify_each_rewrites_page() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        let data = [0xAA_u8; 16];
+        //written, didn't stick, rewritten, matches
+        mock.push_response(&[]);
+        mock.push_response(&[0, 0]);
+        mock.push_response(&[]);
+        mock.push_response(&crc16(&data).to_le_bytes());
+
+        let config = FlasherConfig {
+            verify_each: true,
+            ..Default::default()
+        };
+        let mut flasher = Flasher::with_config(&mock, config).unwrap();
+        let stats = flasher.flash(0x2000, &data, true).unwrap();
+
+        assert_eq!(stats.pages_written, 1);
+        assert_eq!(stats.page_retries, 1);
+        assert_eq!(mock.writes.borrow().len(), 5);
+    }
+
+    #[test]
+    fn verify_each_gives_up() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        mock.push_response(&[]);
+        for _ in 0..PAGE_RETRIES {
+            mock.push_response(&[0, 0]);
+            mock.push_response(&[]);
+        }
+        mock.push_response(&[0, 0]);
+
+        let config = FlasherConfig {
+            verify_each: true,
+            ..Default::default()
+        };
+        let mut flasher = Flasher::with_config(&mock, config).unwrap();
+
+        match flasher.flash(0x2000, &[0xAA; 16], true) {
+            Err(Error::Unverified { mismatches }) => {
+                assert_eq!(mismatches.len(), 1);
+                assert_eq!(mismatches[0].address, 0x2000);
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn unaligned_base_rejected() {
+        let mock = Mock::default();
+        bininfo(&mock);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+
+        assert!(matches!(
+            flasher.flash(0x2004, &[1; 32], true),
+            Err(Error::UnalignedAddress {
+                address: 0x2004,
+                page_size: 16
+            })
+        ));
+        assert!(matches!(
+            flasher.verify(0x2004, &[1; 32]),
+            Err(Error::UnalignedAddress { .. })
+        ));
+        //only bininfo went out
+        assert_eq!(mock.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn flash_resumes_on_reopened_device() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        mock.push_response(&[]);
+        //bininfo and the first page get through
+        mock.go_stale_after(2);
+
+        let reopened = Mock::default();
+        bininfo(&reopened);
+        for _ in 0..2 {
+            reopened.push_response(&[]);
+        }
+        let mut reopened = Some(reopened);
+
+        let mut flasher = Flasher::new(&mock)
+            .unwrap()
+            .with_reopen(move || reopened.take().ok_or(Error::NotFound));
+
+        let stats = flasher.flash(0x2000, &[1; 48], true).unwrap();
+
+        assert_eq!(stats.pages_written, 3);
+        assert_eq!(mock.writes.borrow().len(), 2);
+        let d = flasher.into_reopened().unwrap();
+        let writes = d.writes.borrow();
+        assert_eq!(writes.len(), 3);
+        //carried on from the page that failed
+        assert_eq!(&writes[1][10..14], &0x2010_u32.to_le_bytes());
+        assert_eq!(&writes[2][10..14], &0x2020_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn flash_gives_up_reopening() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        mock.go_stale_after(1);
+
+        let config = FlasherConfig {
+            reopen_attempts: 2,
+            ..Default::default()
+        };
+        let mut flasher = Flasher::with_config(&mock, config)
+            .unwrap()
+            .with_reopen(|| Err(Error::NotFound));
+
+        match flasher.flash(0x2000, &[1; 16], true) {
+            Err(Error::ReopenFailed { attempts: 2, last }) => {
+                assert!(matches!(*last, Error::NotFound))
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn stale_device_without_reopen() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        mock.go_stale_after(1);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+
+        assert!(matches!(
+            flasher.flash(0x2000, &[1; 16], true),
+            Err(Error::Transmission(_))
+        ));
+    }
+
+    #[test]
+    fn verify_short_checksums() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        //a single checksum for two pages, every time it's asked
+        for _ in 0..=crate::CHECKSUM_RETRIES {
+            mock.push_response(&[0x12, 0x34]);
+        }
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+
+        assert!(matches!(
+            flasher.verify(0x2000, &[1; 32]),
+            Err(Error::Parse)
+        ));
+    }
+
+    #[test]
+    fn flash_segments_sharing_a_page() {
+        let mock = Mock::default();
+        bininfo(&mock);
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+
+        //the first's padded last page is the second's first
+        match flasher.flash_segments(&[(0x2000, vec![1; 20]), (0x2010, vec![2; 4])], true) {
+            Err(Error::OverlappingSegments { a, b }) => {
+                assert_eq!(a, 0x2000..0x2020);
+                assert_eq!(b, 0x2010..0x2020);
+            }
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(mock.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn flash_segments_checks_before_writing() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        for _ in 0..3 {
+            mock.push_response(&[]);
+        }
+
+        let mut flasher = Flasher::new(&mock).unwrap();
+
+        assert!(matches!(
+            flasher.flash_segments(&[(0x2000, vec![1; 4]), (0x2004, vec![2; 4])], true),
+            Err(Error::UnalignedAddress { .. })
+        ));
+        assert_eq!(mock.writes.borrow().len(), 1);
+
+        let stats = flasher
+            .flash_segments(&[(0x2000, vec![1; 4]), (0x3000, vec![2; 20])], true)
+            .unwrap();
+
+        assert_eq!(stats.pages_written, 3);
+        let writes = mock.writes.borrow();
+        assert_eq!(&writes[2][10..14], &0x3000_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn sends_events() {
+        let mock = Mock::default();
+        bininfo(&mock);
+
+        let data = [0xAA_u8; 32];
+        let first = crc16(&data[..16]);
+        let mut checksums = first.to_le_bytes().to_vec();
+        checksums.extend_from_slice(&0_u16.to_le_bytes());
+        mock.push_response(&checksums);
+        mock.push_response(&[]);
+
+        let (events, received) = std::sync::mpsc::channel();
+        let mut flasher = Flasher::new(&mock).unwrap().with_events(events);
+        let stats = flasher.flash(0x2000, &data, false).unwrap();
+        drop(flasher);
+
+        assert_eq!(
+            received.iter().collect::<Vec<_>>(),
+            vec![
+                FlashEvent::Started { total_pages: 2 },
+                FlashEvent::ChecksumRead {
+                    from: 0x2000,
+                    count: 2
+                },
+                FlashEvent::PageSkipped { index: 0 },
+                FlashEvent::PageWritten {
+                    index: 1,
+                    address: 0x2010
+                },
+                FlashEvent::Done { stats },
+            ]
+        );
+    }
+
+    #[test]
+    fn batches_up_to_max_message_size_over_small_reports() {
+        let mock = Mock::with_report_size(32);
+        bininfo(&mock);
+        mock.push_response(&[]);
+
+        let config = FlasherConfig {
+            batch_pages: true,
+            ..Default::default()
+        };
+        let mut flasher = Flasher::with_config(&mock, config).unwrap();
+        let stats = flasher.flash(0x2000, &[0xAA_u8; 48], true).unwrap();
+        assert_eq!(stats.pages_written, 3);
+
+        //a 60 byte message is limited by max_message_size, not the 31 byte payload of a report
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[1][1], 31);
+        assert_eq!(writes[2][1], 0x40 | 29);
+    }
+
+    #[test]
+    fn verify_after_reports_pages_that_didnt_commit() {
+        let mock = Mock::default();
+        bininfo(&mock);
+
+        let data = [0xAA_u8; 32];
+        let crc = crc16(&data[..16]);
+        mock.push_response(&[]);
+        mock.push_response(&[]);
+        //first page committed, second still reads erased
+        let mut checksums = crc.to_le_bytes().to_vec();
+        checksums.extend_from_slice(&0_u16.to_le_bytes());
+        mock.push_response(&checksums);
+
+        let config = FlasherConfig {
+            verify_after: true,
+            ..Default::default()
+        };
+        let mut flasher = Flasher::with_config(&mock, config).unwrap();
+
+        match flasher.flash(0x2000, &data, true) {
+            Err(Error::Unverified { mismatches }) => assert_eq!(
+                mismatches,
+                vec![Mismatch {
+                    page_index: 1,
+                    address: 0x2010,
+                    device_crc: 0,
+                    file_crc: crc,
+                }]
+            ),
+            other => panic!("expected Unverified, got {:?}", other),
+        }
+
+        //only the two written pages were checksummed again
+        let writes = mock.writes.borrow();
+        assert_eq!(&writes[3][10..18], &[0x00, 0x20, 0, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn refresh_reads_bininfo_again() {
+        let mock = Mock::default();
+        bininfo(&mock);
+        let mut flasher = Flasher::new(&mock).unwrap();
+        assert_eq!(flasher.bininfo().flash_page_size, 16);
+
+        let mut bininfo = vec![];
+        for word in &[1_u32, 64, 256, 64] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+
+        assert_eq!(flasher.refresh().unwrap().flash_page_size, 64);
+        assert_eq!(flasher.bininfo().flash_num_pages, 256);
+        assert_eq!(mock.writes.borrow().len(), 2);
+    }
+}
@@ -1,17 +1,24 @@
 use crate::{Error, ReadWrite};
 use hidapi::HidDevice;
+use std::convert::TryFrom;
+use std::time::Duration;
 
 impl ReadWrite for HidDevice {
     fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
-        self.write(data).map_err(|e| e.into())
+        Ok(self.write(data)?)
     }
     fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
-        self.read_timeout(buf, 1000).map_err(|e| e.into())
+        Ok(self.read_timeout(buf, 1000)?)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        //hidapi takes milliseconds and treats negative as blocking forever
+        let millis = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        Ok(self.read_timeout(buf, millis)?)
     }
 }
 
 impl From<hidapi::HidError> for Error {
-    fn from(_err: hidapi::HidError) -> Self {
-        Error::Transmission
+    fn from(err: hidapi::HidError) -> Self {
+        Error::Transmission(Box::new(err))
     }
 }
@@ -0,0 +1,113 @@
+use crate::segments::merge_segments;
+use crate::Error;
+use alloc::{vec, vec::Vec};
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+///upper address bits are the field times 16
+const RECORD_EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+///upper 16 address bits are the field
+const RECORD_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+
+///Parse an Intel HEX file into (address, data) segments in address order, no two touching or overlapping. Start address records are ignored, anything after the end of file record too.
+pub fn parse_ihex(text: &str) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let mut records = vec![];
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let record = decode_record(line)?;
+        let record_type = record[3];
+
+        records.push(record);
+        if record_type == RECORD_EOF {
+            break;
+        }
+    }
+
+    let mut base = 0_u32;
+    let mut chunks = vec![];
+
+    for record in &records {
+        let offset = u32::from(u16::from_be_bytes([record[1], record[2]]));
+        let data = &record[4..record.len() - 1];
+
+        match record[3] {
+            RECORD_DATA => chunks.push((base.wrapping_add(offset), data)),
+            RECORD_EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => {
+                base = u32::from(u16::from_be_bytes([data[0], data[1]])) << 4
+            }
+            RECORD_EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                base = u32::from(u16::from_be_bytes([data[0], data[1]])) << 16
+            }
+            RECORD_EXTENDED_SEGMENT_ADDRESS | RECORD_EXTENDED_LINEAR_ADDRESS => {
+                return Err(Error::Parse)
+            }
+            _ => (),
+        }
+    }
+
+    merge_segments(chunks)
+}
+
+///Bytes of a single :LLAAAATT..CC record with its length and checksum checked.
+fn decode_record(line: &str) -> Result<Vec<u8>, Error> {
+    let hex = line.strip_prefix(':').ok_or(Error::Parse)?;
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(Error::Parse);
+    }
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| Error::Parse))
+        .collect::<Result<Vec<u8>, Error>>()?;
+
+    //length, 2 address, type and checksum around the data
+    if bytes.len() < 5 || bytes.len() != 5 + bytes[0] as usize {
+        return Err(Error::Parse);
+    }
+
+    if bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(Error::Parse);
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_address_and_gaps() {
+        let hex = ":020000040800F2\n\
+                   :0400000001020304F2\n\
+                   :0400040005060708DE\n\
+                   :0400100009000000E3\n\
+                   :04000005080001C12D\n\
+                   :00000001FF\n";
+
+        assert_eq!(
+            parse_ihex(hex).unwrap(),
+            vec![
+                (0x0800_0000, vec![1, 2, 3, 4, 5, 6, 7, 8]),
+                (0x0800_0010, vec![9, 0, 0, 0])
+            ]
+        );
+    }
+
+    #[test]
+    fn segment_address() {
+        let hex = ":020000021000EC\n:0100000042BD\n:00000001FF\n";
+
+        assert_eq!(parse_ihex(hex).unwrap(), vec![(0x1_0000, vec![0x42])]);
+    }
+
+    #[test]
+    fn rejects_bad_records() {
+        //bad checksum
+        assert!(parse_ihex(":0100000042BE\n").is_err());
+        //says 2 data bytes but carries 1
+        assert!(parse_ihex(":0200000042BC\n").is_err());
+        //not a record
+        assert!(parse_ihex("0100000042BD\n").is_err());
+    }
+}
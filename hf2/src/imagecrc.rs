@@ -0,0 +1,59 @@
+use crate::{read_memory, Error, ReadWrite};
+
+///CRC-32 as zlib and the crc32 tool compute it, reflected polynomial 0xEDB88320 starting from and finally xored with 0xFFFFFFFF, aka ISO-HDLC.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+///crc32 of length bytes of memory at address, read back with READ_WORDS, to compare against crc32 of the image written there. Coarser than checksum_pages but over the bytes themselves rather than a checksum per page. words_per_request as for read_memory.
+pub fn memory_crc32(
+    d: &impl ReadWrite,
+    address: u32,
+    length: u32,
+    words_per_request: Option<u32>,
+) -> Result<u32, Error> {
+    Ok(crc32(&read_memory(d, address, length, words_per_request)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+    use alloc::vec;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn memory_crc32_of_readback() {
+        let mock = Mock::default();
+        let mut bininfo = vec![];
+        for word in &[1_u32, 512, 1024, 256] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+        mock.push_response(b"12345678");
+
+        //the padding up to a whole word isn't part of it
+        assert_eq!(
+            memory_crc32(&mock, 0x2000, 7, None).unwrap(),
+            crc32(b"1234567")
+        );
+    }
+}
@@ -1,45 +1,114 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, strip_length_header, Command};
+use crate::{Error, ReadWrite};
+use alloc::collections::BTreeMap;
+use alloc::{string::String, vec};
 use scroll::{ctx, Pread, LE};
 
 /// Various device information. The result is a character array. See INFO_UF2.TXT in UF2 format for details.
-pub fn info(d: &hidapi::HidDevice) -> Result<InfoResponse, Error> {
-    xmit(Command::new(0x0002, 0, vec![]), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => return (data.as_slice()).pread_with(0, LE),
-        Ok(_) => return Err(Error::CommandNotRecognized),
-        Err(e) => return Err(e),
-    }
+pub fn info(d: &impl ReadWrite) -> Result<InfoResponse, Error> {
+    let rsp = send(&Command::new(0x0002, vec![]), d)?;
+
+    rsp.data.as_slice().pread_with(0, LE)
 }
 
-///Response to the info command
-#[derive(Debug, PartialEq)]
+///Response to the info command, the INFO_UF2.TXT text along with the well known fields parsed from its `Key: Value` lines.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InfoResponse {
+    ///the whole text as the device sent it, for boards that format it unusually
     pub info: String,
+    pub model: Option<String>,
+    pub board_id: Option<String>,
+    ///from a `Bootloader` line, or otherwise what follows `UF2 Bootloader` on the first line, usually the version
+    pub bootloader: Option<String>,
+    pub date: Option<String>,
+    ///every other `Key: Value` line
+    pub raw: BTreeMap<String, String>,
+}
+
+impl InfoResponse {
+    ///Parse the fields out of info.
+    pub fn new(info: impl Into<String>) -> Self {
+        let info = info.into();
+        let mut response = InfoResponse {
+            info: String::new(),
+            model: None,
+            board_id: None,
+            bootloader: None,
+            date: None,
+            raw: BTreeMap::new(),
+        };
+
+        for line in info.lines() {
+            let mut split = line.splitn(2, ':');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim().into()),
+                _ => continue,
+            };
+
+            if key.eq_ignore_ascii_case("Model") {
+                response.model = Some(value);
+            } else if key.eq_ignore_ascii_case("Board-ID") {
+                response.board_id = Some(value);
+            } else if key.eq_ignore_ascii_case("Bootloader") {
+                response.bootloader = Some(value);
+            } else if key.eq_ignore_ascii_case("Date") {
+                response.date = Some(value);
+            } else {
+                response.raw.insert(key.into(), value);
+            }
+        }
+
+        if response.bootloader.is_none() {
+            response.bootloader = info
+                .lines()
+                .next()
+                .and_then(|line| line.trim().strip_prefix("UF2 Bootloader "))
+                .map(|version| version.trim().into());
+        }
+
+        response.info = info;
+        response
+    }
+
+    ///Value of a `Key: Value` line such as `Model` or `Board-ID`, the key is matched case insensitively. Looked up in the parsed fields, so `Bootloader` may come from the first line, then in raw.
+    pub fn field(&self, key: &str) -> Option<&str> {
+        let parsed = [
+            ("Model", &self.model),
+            ("Board-ID", &self.board_id),
+            ("Bootloader", &self.bootloader),
+            ("Date", &self.date),
+        ];
+        if let Some((_, value)) = parsed.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            return value.as_deref();
+        }
+
+        self.raw
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
 }
 
 impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for InfoResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
-        let mut bytes = vec![0; this.len()];
+        let header = this.len() - strip_length_header(this).len();
+        let mut bytes = vec![0; this.len() - header];
 
-        let mut offset = 0;
+        let mut offset = header;
         this.gread_inout_with(&mut offset, &mut bytes, le)?;
 
         let info = core::str::from_utf8(&bytes)?;
 
-        Ok((InfoResponse { info: info.into() }, offset))
+        Ok((InfoResponse::new(info), offset))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::vec::Vec;
 
     #[test]
     fn parse_response() {
@@ -52,12 +121,61 @@ mod tests {
             0x61, 0x6D, 0x65, 0x72, 0x2D, 0x4D, 0x34, 0x0D, 0x0A,
         ];
 
-        let info_result = InfoResponse {
-info: "UF2 Bootloader v3.6.0 SFHWRO\r\nModel: PyGamer\r\nBoard-ID: SAMD51J19A-PyGamer-M4\r\n".into()
-        };
+        let info_result = InfoResponse::new(
+            "UF2 Bootloader v3.6.0 SFHWRO\r\nModel: PyGamer\r\nBoard-ID: SAMD51J19A-PyGamer-M4\r\n",
+        );
 
         let res: InfoResponse = (data.as_slice()).pread_with::<InfoResponse>(0, LE).unwrap();
 
         assert_eq!(res, info_result);
     }
+
+    #[test]
+    fn parse_response_with_length_header() {
+        let text = "UF2 Bootloader v3.6.0 SFHWRO\r\nModel: PyGamer\r\n";
+
+        let mut data: Vec<u8> = (text.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+
+        let res: InfoResponse = (data.as_slice()).pread_with::<InfoResponse>(0, LE).unwrap();
+
+        assert_eq!(res, InfoResponse::new(text));
+    }
+
+    #[test]
+    fn fields() {
+        let info = InfoResponse::new(
+            "UF2 Bootloader v3.6.0 SFHWRO\r\nModel: PyGamer\r\nBoard-ID: SAMD51J19A-PyGamer-M4\r\nFlash-Size: 512k\r\n",
+        );
+
+        assert_eq!(info.field("Model"), Some("PyGamer"));
+        assert_eq!(info.field("board-id"), Some("SAMD51J19A-PyGamer-M4"));
+        assert_eq!(info.field("bootloader"), Some("v3.6.0 SFHWRO"));
+        assert_eq!(info.field("flash-size"), Some("512k"));
+        assert_eq!(info.field("Date"), None);
+    }
+
+    #[test]
+    fn parsed_fields() {
+        let info = InfoResponse::new(
+            "UF2 Bootloader v3.6.0 SFHWRO\r\nModel: PyGamer\r\nBoard-ID: SAMD51J19A-PyGamer-M4\r\nDate: Jun 10 2019\r\nFlash-Size: 512k\r\n",
+        );
+
+        assert_eq!(info.model.as_deref(), Some("PyGamer"));
+        assert_eq!(info.board_id.as_deref(), Some("SAMD51J19A-PyGamer-M4"));
+        assert_eq!(info.bootloader.as_deref(), Some("v3.6.0 SFHWRO"));
+        assert_eq!(info.date.as_deref(), Some("Jun 10 2019"));
+        assert_eq!(info.raw.len(), 1);
+        assert_eq!(info.raw["Flash-Size"], "512k");
+    }
+
+    #[test]
+    fn unusual_text() {
+        let info = InfoResponse::new("my bootloader, no fields here");
+
+        assert_eq!(info.model, None);
+        assert_eq!(info.bootloader, None);
+        assert!(info.raw.is_empty());
+        assert_eq!(info.info, "my bootloader, no fields here");
+    }
 }
@@ -1,3 +1,15 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+
+///Async versions of the commands, each device owned by a worker thread.
+#[cfg(feature = "async")]
+mod asyncdevice;
+#[cfg(feature = "async")]
+pub use asyncdevice::*;
+
 /// This command states the current mode of the device:
 mod bininfo;
 pub use bininfo::*;
@@ -6,14 +18,53 @@ pub use bininfo::*;
 mod checksumpages;
 pub use checksumpages::*;
 
+///Finding and opening hid devices with errors that say why it failed.
+#[cfg(feature = "hidapi")]
+mod device;
+#[cfg(feature = "hidapi")]
+pub use device::*;
+
 ///Return internal log buffer if any. The result is a character array.
 mod dmesg;
 pub use dmesg::*;
 
+///Wiping a region of flash by writing zero pages.
+mod erase;
+pub use erase::*;
+
+///Events and results reported while flashing.
+mod flash;
+pub use flash::*;
+
+///Flashing and verifying whole images with a single bininfo.
+#[cfg(feature = "std")]
+mod flasher;
+#[cfg(feature = "std")]
+pub use flasher::*;
+
+///Parsing Intel HEX files into the segments they'd write.
+mod ihex;
+pub use ihex::*;
+
+///CRC-32 of a whole image, in a file or read back from the device.
+mod imagecrc;
+pub use imagecrc::*;
+
 /// Various device information. The result is a character array. See INFO_UF2.TXT in UF2 format for details.
 mod info;
 pub use info::*;
 
+///Framing messages into hf2 packets and joining them back together, independent of the transport.
+pub mod packet;
+
+///Splitting a binary into flash pages.
+mod pages;
+pub use pages::*;
+
+///Read the entire flash into a Vec using bininfo and chunked read_words.
+mod readflash;
+pub use readflash::*;
+
 ///Read a number of words from memory. Memory is read word by word (and not byte by byte), and target_addr must be suitably aligned. This is to support reading of special IO regions.
 mod readwords;
 pub use readwords::*;
@@ -26,10 +77,31 @@ pub use resetintoapp::*;
 mod resetintobootloader;
 pub use resetintobootloader::*;
 
+///hf2 over a USB CDC serial port.
+#[cfg(feature = "serial")]
+mod serial;
+#[cfg(feature = "serial")]
+pub use serial::*;
+
+///Joining chunks of an image file into contiguous segments.
+mod segments;
+
+///Motorola S-record encoding of memory dumps.
+mod srec;
+pub use srec::*;
+
 /// When issued in bootloader mode, it has no effect. In user-space mode it causes handover to bootloader. A BININFO command can be issued to verify that.
 mod startflash;
 pub use startflash::*;
 
+///Parsing uf2 files into the segments they'd write.
+mod uf2;
+pub use uf2::*;
+
+///Sanity checks of the vector table at the start of an image.
+mod vectors;
+pub use vectors::*;
+
 ///Write a single page of flash memory. No Result.
 mod writeflashpage;
 pub use writeflashpage::*;
@@ -41,21 +113,184 @@ pub use writewords::*;
 /// Errors and traits to build a command
 mod command;
 
-#[derive(Clone, Debug)]
+pub use command::{send_raw, CommandResponseStatus, SerialStream};
+
+#[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("invalid arguments")]
     Arguments,
+    ///a response or file that doesn't hold what it should
+    #[error("malformed response or file")]
     Parse,
-    CommandNotRecognized,
-    Execution,
-    Sequence,
-    Transmission,
+    #[error("couldn't decode: {0}")]
+    Decode(#[cfg_attr(feature = "std", from)] scroll::Error),
+    #[error("not valid utf8: {0}")]
+    Utf8(#[from] core::str::Utf8Error),
+    #[cfg(feature = "std")]
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    ///the device answered command with something other than success
+    #[error("command {command:#06X} failed: {status}, status info {status_info}")]
+    Status {
+        command: u32,
+        status: CommandResponseStatus,
+        status_info: u8,
+    },
+    ///a packet's header claims more payload than a report can hold, or than the packet handed over
+    #[error("packet claims {claimed} bytes of payload but only {available} fit")]
+    ShortRead { claimed: usize, available: usize },
+    ///a response repeating another tag than the command just sent, left over from an earlier command
+    #[error("response tagged {got} doesn't belong to the command tagged {expected}")]
+    TagMismatch { expected: u16, got: u16 },
+    #[error("usb transfer failed: {0}")]
+    Transmission(#[source] Box<dyn core::error::Error + Send + Sync>),
+    ///the os refused access to the device, usually missing udev rules on linux
+    #[error("permission denied opening the device")]
+    PermissionDenied,
+    ///nothing enumerated matching what we were looking for
+    #[error("no matching device found")]
+    NotFound,
+    ///an image written at its address would run past the end of flash, both in bytes from the start of flash
+    #[error("image needs {needed} bytes of flash but the device only has {available}")]
+    ImageTooLarge { needed: u64, available: u64 },
+    ///pages are written whole, a segment starting part way into one would wipe the start of that page
+    #[error("address {address:#X} isn't on a {page_size} byte page boundary")]
+    UnalignedAddress { address: u32, page_size: u32 },
+    ///two segments of an image, as address ranges padded to whole pages, would write the same pages
+    #[error("segments {a:#X?} and {b:#X?} overlap")]
+    OverlappingSegments {
+        a: core::ops::Range<u32>,
+        b: core::ops::Range<u32>,
+    },
+    ///bininfo values nothing can be computed from, like a zero page size or no room for a single checksum
+    #[error("device reported an unusable bininfo: {0}")]
+    InvalidBinInfo(&'static str),
+    ///a uf2 built for one family about to be flashed to a device reporting another
+    #[error("image is built for {image} but the device reports {device}")]
+    FamilyMismatch { image: FamilyId, device: FamilyId },
+    ///pages acknowledged as written whose checksum still doesn't match the image when read back
+    #[error("{} written pages don't match the image", mismatches.len())]
+    Unverified { mismatches: Vec<Mismatch> },
+    ///an AsyncDevice's worker thread has exited, usually because a command panicked
+    #[error("the device's worker thread is gone")]
+    WorkerGone,
+    ///a word written with WRITE_WORDS reads back differently, usually a protected or read only address
+    #[error("word at {address:#010X} doesn't read back as written")]
+    WriteVerifyFailed { address: u32 },
+    ///a device reset into its bootloader didn't come back answering in bootloader mode
+    #[error("device didn't come back in bootloader mode within {}ms, try double tapping reset", timeout.as_millis())]
+    NoBootloader { timeout: core::time::Duration },
+    ///a flash lost its device's handle and couldn't get a working one back from its reopen hook
+    #[error("device couldn't be reopened after {attempts} attempts: {last}")]
+    ReopenFailed {
+        attempts: u32,
+        #[source]
+        last: Box<Error>,
+    },
+    ///no packet arrived within the transport's timeout
+    #[error("timed out waiting for the device")]
+    Timeout,
 }
 
+impl Error {
+    ///Whether the device answered that it doesn't understand the command, rather than it failing to carry it out or the transfer going wrong.
+    pub fn is_unsupported(&self) -> bool {
+        matches!(
+            self,
+            Error::Status {
+                status: CommandResponseStatus::ParseError,
+                ..
+            }
+        )
+    }
+}
+
+//scroll's error only implements std::error::Error with its std feature, which thiserror's from needs
+#[cfg(not(feature = "std"))]
+impl From<scroll::Error> for Error {
+    fn from(e: scroll::Error) -> Self {
+        Error::Decode(e)
+    }
+}
+
+///HID report size the hf2 spec assumes, one header byte and 63 of payload.
+pub const REPORT_SIZE: usize = 64;
+///Smallest report that still fits the header byte, the 8 byte command header and a byte of data.
+pub const MIN_REPORT_SIZE: usize = 10;
+
 ///trait to implement HID devices
 pub trait ReadWrite {
     fn hf2_write(&self, data: &[u8]) -> Result<usize, Error>;
     fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error>;
+    ///HID report size in bytes, not counting the report id. Between MIN_REPORT_SIZE and REPORT_SIZE, the 6 bit packet length can't describe more.
+    fn report_size(&self) -> usize {
+        REPORT_SIZE
+    }
+    ///Read waiting at most timeout for a report, 0 when none arrived. Transports without their own deadline just read.
+    fn hf2_read_timeout(
+        &self,
+        buf: &mut [u8],
+        _timeout: core::time::Duration,
+    ) -> Result<usize, Error> {
+        self.hf2_read(buf)
+    }
+    ///How long to wait for each packet of a response before giving up with Error::Timeout. None keeps hf2_read's own behavior.
+    fn timeout(&self) -> Option<core::time::Duration> {
+        None
+    }
+    ///How many more times a command is sent when its response times out or reports a failure. Pages are written whole, so resending a write is safe.
+    fn retries(&self) -> u32 {
+        0
+    }
+    ///Called with serial output the device sends in between command responses. Logged by default.
+    fn serial(&self, stream: SerialStream, data: &[u8]) {
+        log::debug!(
+            "{:?}: {}",
+            stream,
+            alloc::string::String::from_utf8_lossy(data)
+        );
+    }
 }
 
 #[cfg(feature = "hidapi")]
 mod hidapi_trait;
+
+#[cfg(test)]
+mod mock;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scroll::{Pread, LE};
+
+    fn decode(data: &[u8]) -> Result<u32, Error> {
+        Ok(data.pread_with(0, LE)?)
+    }
+
+    fn utf8(data: &[u8]) -> Result<&str, Error> {
+        Ok(core::str::from_utf8(data)?)
+    }
+
+    #[cfg(feature = "std")]
+    fn io() -> Result<(), Error> {
+        Err(std::io::Error::from(std::io::ErrorKind::NotFound))?
+    }
+
+    #[cfg(feature = "hidapi")]
+    fn hid() -> Result<(), Error> {
+        Err(hidapi::HidError::HidApiError {
+            message: "gone".into(),
+        })?
+    }
+
+    #[test]
+    fn errors_convert_with_question_mark() {
+        assert!(matches!(decode(&[1, 2]), Err(Error::Decode(_))));
+        assert_eq!(decode(&[1, 0, 0, 0]).unwrap(), 1);
+        assert!(matches!(utf8(&[0xFF]), Err(Error::Utf8(_))));
+        #[cfg(feature = "std")]
+        assert!(matches!(io(), Err(Error::Io(_))));
+        #[cfg(feature = "hidapi")]
+        assert!(matches!(hid(), Err(Error::Transmission(_))));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{CommandResponseStatus, Error, ReadWrite, SerialStream, REPORT_SIZE};
+use alloc::collections::VecDeque;
+use alloc::{vec, vec::Vec};
+use core::cell::{Cell, RefCell};
+
+enum Read {
+    ///played back as is
+    Report(Vec<u8>),
+    ///a single packet response, tagged when it's read with the tag of the last command written
+    Response {
+        status: CommandResponseStatus,
+        status_info: u8,
+        data: Vec<u8>,
+    },
+}
+
+///Transport for tests, plays back queued reports and records everything written along with any serial output.
+pub(crate) struct Mock {
+    reads: RefCell<VecDeque<Read>>,
+    ///tag of the last command written, and whether the next packet written starts a new one
+    tag: Cell<u16>,
+    message_done: Cell<bool>,
+    pub(crate) writes: RefCell<Vec<Vec<u8>>>,
+    pub(crate) serial: RefCell<Vec<(SerialStream, Vec<u8>)>>,
+    report_size: usize,
+    retries: u32,
+    ///writes fail once this many have gone through, like a handle to a device that went away
+    stale_after: Cell<Option<usize>>,
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Mock::with_report_size(REPORT_SIZE)
+    }
+}
+
+impl Mock {
+    pub(crate) fn with_report_size(report_size: usize) -> Self {
+        Mock {
+            reads: RefCell::default(),
+            tag: Cell::new(0),
+            message_done: Cell::new(true),
+            writes: RefCell::default(),
+            serial: RefCell::default(),
+            report_size,
+            retries: 0,
+            stale_after: Cell::new(None),
+        }
+    }
+
+    pub(crate) fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    ///Fail every write after the next writes ones.
+    #[cfg(feature = "std")]
+    pub(crate) fn go_stale_after(&self, writes: usize) {
+        self.stale_after
+            .set(Some(self.writes.borrow().len() + writes));
+    }
+
+    ///LE bytes of the tag of the last command written, to compare a write against.
+    pub(crate) fn tag(&self) -> [u8; 2] {
+        self.tag.get().to_le_bytes()
+    }
+
+    ///Queue a single report to be returned by a future read.
+    pub(crate) fn push_read(&self, report: Vec<u8>) {
+        self.reads.borrow_mut().push_back(Read::Report(report));
+    }
+
+    ///Queue a successful single packet response carrying data, answering whichever command was written last.
+    pub(crate) fn push_response(&self, data: &[u8]) {
+        self.reads.borrow_mut().push_back(Read::Response {
+            status: CommandResponseStatus::Success,
+            status_info: 0,
+            data: data.to_vec(),
+        });
+    }
+
+    ///Queue a response failing with status, answering whichever command was written last.
+    pub(crate) fn push_failure(&self, status: CommandResponseStatus, status_info: u8) {
+        self.reads.borrow_mut().push_back(Read::Response {
+            status,
+            status_info,
+            data: vec![],
+        });
+    }
+}
+
+impl ReadWrite for Mock {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        if self
+            .stale_after
+            .get()
+            .is_some_and(|writes| self.writes.borrow().len() >= writes)
+        {
+            return Err(Error::Transmission("device went away".into()));
+        }
+
+        //report id, packet header, then the command id and tag in the first packet of a command
+        if self.message_done.get() && data.len() >= 8 {
+            self.tag.set(u16::from_le_bytes([data[6], data[7]]));
+        }
+        self.message_done
+            .set(data.len() > 1 && data[1] & 0xC0 == 0x40);
+
+        self.writes.borrow_mut().push(data.to_vec());
+        Ok(data.len())
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let report = match self.reads.borrow_mut().pop_front() {
+            Some(Read::Report(report)) => report,
+            Some(Read::Response {
+                status,
+                status_info,
+                data,
+            }) => {
+                let mut report = vec![0x40 | (data.len() + 4) as u8];
+                report.extend_from_slice(&self.tag.get().to_le_bytes());
+                report.extend_from_slice(&[status as u8, status_info]);
+                report.extend_from_slice(&data);
+                report
+            }
+            None => return Ok(0),
+        };
+
+        buf[..report.len()].copy_from_slice(&report);
+        Ok(report.len())
+    }
+    fn report_size(&self) -> usize {
+        self.report_size
+    }
+    fn retries(&self) -> u32 {
+        self.retries
+    }
+    fn serial(&self, stream: SerialStream, data: &[u8]) {
+        self.serial.borrow_mut().push((stream, data.to_vec()));
+    }
+}
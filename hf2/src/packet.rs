@@ -0,0 +1,195 @@
+use crate::{Error, SerialStream};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+///The 6 bit length in a packet's header byte can't describe more payload than this.
+pub const MAX_PAYLOAD: usize = 63;
+
+#[derive(Debug, PartialEq)]
+enum PacketType {
+    //Inner packet of a command message
+    Inner = 0,
+    //Final packet of a command message
+    Final = 1,
+    //Serial stdout
+    StdOut = 2,
+    //Serial stderr
+    Stderr = 3,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = Error;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(PacketType::Inner),
+            1 => Ok(PacketType::Final),
+            2 => Ok(PacketType::StdOut),
+            3 => Ok(PacketType::Stderr),
+            _ => Err(Error::Parse),
+        }
+    }
+}
+
+///Split message, a whole command or response, into packets of at most max_packet bytes, header byte included. Every packet but the last is marked inner, an empty message is a single empty final packet.
+pub fn encode(message: &[u8], max_packet: usize) -> Vec<Vec<u8>> {
+    assert!(max_packet > 1, "max_packet must leave room for a payload");
+
+    let payload = (max_packet - 1).min(MAX_PAYLOAD);
+    let mut chunks: Vec<&[u8]> = message.chunks(payload).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    let last = chunks.len() - 1;
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let ptype = if i == last {
+                PacketType::Final
+            } else {
+                PacketType::Inner
+            };
+
+            let mut packet = Vec::with_capacity(chunk.len() + 1);
+            packet.push((ptype as u8) << 6 | chunk.len() as u8);
+            packet.extend_from_slice(chunk);
+            packet
+        })
+        .collect()
+}
+
+///What a packet handed to Reassembler::push amounted to.
+#[derive(Debug, PartialEq)]
+pub enum Reassembled<'a> {
+    ///an inner packet, the message isn't complete yet
+    Pending,
+    ///the final packet arrived, the whole message
+    Message(Vec<u8>),
+    ///serial output, which isn't part of any message
+    Serial(SerialStream, &'a [u8]),
+}
+
+///Joins the packets of a message back together as they're read one at a time.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    message: Vec<u8>,
+}
+
+impl Reassembler {
+    ///Add a packet as read, header byte first. Bytes past the length in the header are ignored, the device is allowed to send them. Error::ShortRead when the header claims more than the packet holds.
+    pub fn push<'a>(&mut self, packet: &'a [u8]) -> Result<Reassembled<'a>, Error> {
+        let header = *packet.first().ok_or(Error::Parse)?;
+        let ptype = PacketType::try_from(header >> 6)?;
+        let len = (header & 0x3F) as usize;
+
+        log::debug!("rx ptype: {:?} len: {:?}", ptype, len);
+
+        let payload = packet.get(1..len + 1).ok_or(Error::ShortRead {
+            claimed: len,
+            available: packet.len() - 1,
+        })?;
+
+        match ptype {
+            PacketType::Inner => {
+                self.message.extend_from_slice(payload);
+                Ok(Reassembled::Pending)
+            }
+            PacketType::Final => {
+                self.message.extend_from_slice(payload);
+                Ok(Reassembled::Message(core::mem::take(&mut self.message)))
+            }
+            PacketType::StdOut => Ok(Reassembled::Serial(SerialStream::Stdout, payload)),
+            PacketType::Stderr => Ok(Reassembled::Serial(SerialStream::Stderr, payload)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn round_trip_over_several_packets() {
+        let message: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let packets = encode(&message, 64);
+
+        assert_eq!(packets.len(), 4);
+        assert_eq!(packets[0][0], 0x3F);
+        assert_eq!(packets[3][0], 0x40 | 11);
+
+        let mut reassembler = Reassembler::default();
+        let mut reassembled = vec![];
+        for packet in &packets {
+            reassembled.push(reassembler.push(packet).unwrap());
+        }
+
+        assert_eq!(
+            reassembled[..3],
+            [
+                Reassembled::Pending,
+                Reassembled::Pending,
+                Reassembled::Pending
+            ]
+        );
+        assert_eq!(reassembled[3], Reassembled::Message(message));
+    }
+
+    #[test]
+    fn small_packets_and_empty_message() {
+        let packets = encode(&[1, 2, 3, 4, 5], 3);
+        assert_eq!(
+            packets,
+            vec![vec![0x02, 1, 2], vec![0x02, 3, 4], vec![0x41, 5]]
+        );
+
+        assert_eq!(encode(&[], 64), vec![vec![0x40]]);
+    }
+
+    #[test]
+    fn serial_in_between_and_padding() {
+        let mut reassembler = Reassembler::default();
+
+        assert_eq!(
+            reassembler.push(&[0x02, 1, 2]).unwrap(),
+            Reassembled::Pending
+        );
+        assert_eq!(
+            reassembler.push(&[0xC2, b'h', b'i', 0, 0]).unwrap(),
+            Reassembled::Serial(SerialStream::Stderr, b"hi")
+        );
+        //the rest of the report is padding
+        assert_eq!(
+            reassembler.push(&[0x41, 3, 0, 0, 0]).unwrap(),
+            Reassembled::Message(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn rejects_length_past_packet() {
+        let mut reassembler = Reassembler::default();
+
+        assert!(reassembler.push(&[0x43, 1, 2]).is_err());
+        assert!(reassembler.push(&[]).is_err());
+    }
+
+    #[test]
+    fn same_message_at_32_and_64_bytes() {
+        let message: Vec<u8> = (0..100).map(|i| i as u8).collect();
+
+        for (max_packet, count) in &[(32, 4), (64, 2)] {
+            let packets = encode(&message, *max_packet);
+            assert_eq!(packets.len(), *count);
+            assert!(packets.iter().all(|packet| packet.len() <= *max_packet));
+
+            let mut reassembler = Reassembler::default();
+            let last = packets
+                .iter()
+                .map(|packet| reassembler.push(packet).unwrap())
+                .last();
+            assert_eq!(last, Some(Reassembled::Message(message.clone())));
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{BinInfoResponse, Error, FamilyId};
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+///Iterator over the flash pages of a binary, see pages.
+#[derive(Debug, Clone)]
+pub struct Pages<'a> {
+    binary: &'a [u8],
+    address: u32,
+    page_size: usize,
+    index: usize,
+}
+
+///Split binary into page_size pages written from address on, yielding each page's target address and contents. Whole pages are borrowed, only a final partial page is copied to pad it with zeros.
+pub fn pages(binary: &[u8], address: u32, page_size: u32) -> Pages<'_> {
+    assert!(page_size > 0, "page_size must be non zero");
+
+    Pages {
+        binary,
+        address,
+        page_size: page_size as usize,
+        index: 0,
+    }
+}
+
+///The pages of binary in range, counting from its first page, as a single run. Borrowed unless it takes in the last page, which is padded with zeros like pages does.
+pub fn page_run(binary: &[u8], page_size: u32, range: Range<usize>) -> Cow<'_, [u8]> {
+    let page_size = page_size as usize;
+    let start = range.start * page_size;
+    let end = range.end * page_size;
+
+    if end <= binary.len() {
+        Cow::Borrowed(&binary[start..end])
+    } else {
+        let mut run = binary[start.min(binary.len())..].to_vec();
+        run.resize(end - start, 0);
+        Cow::Owned(run)
+    }
+}
+
+impl BinInfoResponse {
+    ///Split binary into this device's flash pages, see pages.
+    pub fn pages<'a>(&self, binary: &'a [u8], address: u32) -> Pages<'a> {
+        pages(binary, address, self.flash_page_size)
+    }
+
+    ///Where flash starts in the device's address space, 0x08000000 on STM32 and 0 for everything else.
+    pub fn flash_base(&self) -> u32 {
+        match self.family_id {
+            Some(FamilyId::STM32F103) | Some(FamilyId::STM32F401) => 0x0800_0000,
+            _ => 0,
+        }
+    }
+
+    ///Error::UnalignedAddress unless address starts one of this device's flash pages.
+    pub fn check_aligned(&self, address: u32) -> Result<(), Error> {
+        if !address.is_multiple_of(self.flash_page_size) {
+            return Err(Error::UnalignedAddress {
+                address,
+                page_size: self.flash_page_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    ///Error::ImageTooLarge unless len bytes written from address, padded to whole pages, end within flash.
+    pub fn check_fits(&self, address: u32, len: usize) -> Result<(), Error> {
+        let page_size = u64::from(self.flash_page_size);
+        let padded = (len as u64).div_ceil(page_size) * page_size;
+        let needed = (u64::from(address) + padded).saturating_sub(u64::from(self.flash_base()));
+        let available = page_size * u64::from(self.flash_num_pages);
+
+        if needed > available {
+            return Err(Error::ImageTooLarge { needed, available });
+        }
+
+        Ok(())
+    }
+
+    ///Check segments, (address, data) in any order, can each be written page by page without touching the others. Every segment starts on a page boundary, fits in flash, and no two share a page.
+    pub fn check_segments<T: AsRef<[u8]>>(&self, segments: &[(u32, T)]) -> Result<(), Error> {
+        for (address, data) in segments {
+            self.check_aligned(*address)?;
+            self.check_fits(*address, data.as_ref().len())?;
+        }
+
+        self.check_overlap(segments)
+    }
+
+    ///Error::OverlappingSegments with the first two of segments, as whole pages, that would write the same page. Whichever is written last would win that page.
+    pub fn check_overlap<T: AsRef<[u8]>>(&self, segments: &[(u32, T)]) -> Result<(), Error> {
+        let page_size = u64::from(self.flash_page_size);
+
+        let mut ranges: Vec<_> = segments
+            .iter()
+            .map(|(address, data)| {
+                let start = u64::from(*address) / page_size * page_size;
+                let end = (u64::from(*address) + data.as_ref().len() as u64).div_ceil(page_size)
+                    * page_size;
+                start as u32..end.min(u64::from(u32::MAX)) as u32
+            })
+            .collect();
+
+        ranges.sort_by_key(|range| range.start);
+        for pair in ranges.windows(2) {
+            if pair[0].end > pair[1].start {
+                return Err(Error::OverlappingSegments {
+                    a: pair[0].clone(),
+                    b: pair[1].clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Pages<'a> {
+    type Item = (u32, Cow<'a, [u8]>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.index * self.page_size;
+        if start >= self.binary.len() {
+            return None;
+        }
+
+        let target_address = self.address + start as u32;
+        let end = start + self.page_size;
+
+        let page = if end <= self.binary.len() {
+            Cow::Borrowed(&self.binary[start..end])
+        } else {
+            let mut page = self.binary[start..].to_vec();
+            page.resize(self.page_size, 0);
+            Cow::Owned(page)
+        };
+
+        self.index += 1;
+        Some((target_address, page))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pages = self.binary.len().div_ceil(self.page_size) - self.index;
+        (pages, Some(pages))
+    }
+}
+
+impl<'a> ExactSizeIterator for Pages<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn pads_last_page() {
+        let binary: Vec<u8> = (1..=10).collect();
+        let pages: Vec<(u32, Cow<[u8]>)> = pages(&binary, 0x4000, 4).collect();
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0], (0x4000, Cow::Borrowed(&[1, 2, 3, 4][..])));
+        assert_eq!(pages[1].0, 0x4004);
+        assert!(matches!(pages[1].1, Cow::Borrowed(_)));
+        assert_eq!(pages[2].0, 0x4008);
+        assert_eq!(&pages[2].1[..], &[9, 10, 0, 0]);
+    }
+
+    #[test]
+    fn page_run_borrows_whole_pages() {
+        let binary: Vec<u8> = (1..=10).collect();
+
+        assert!(matches!(
+            page_run(&binary, 4, 0..2),
+            Cow::Borrowed(&[1, 2, 3, 4, 5, 6, 7, 8])
+        ));
+        assert_eq!(&page_run(&binary, 4, 1..3)[..], &[5, 6, 7, 8, 9, 10, 0, 0]);
+    }
+
+    #[test]
+    fn exact_len() {
+        let binary = vec![0; 8];
+
+        assert_eq!(pages(&binary, 0, 4).len(), 2);
+        assert_eq!(pages(&binary, 0, 3).len(), 3);
+        assert_eq!(pages(&[], 0, 4).len(), 0);
+    }
+
+    #[test]
+    fn check_fits_flash() {
+        let bininfo = BinInfoResponse {
+            mode: crate::BinInfoMode::Bootloader,
+            flash_page_size: 256,
+            flash_num_pages: 16,
+            max_message_size: 512,
+            family_id: None,
+        };
+
+        assert!(bininfo.check_fits(0x0800, 0x800).is_ok());
+        //a partial last page still takes a whole one
+        assert!(matches!(
+            bininfo.check_fits(0x0800, 0x801),
+            Err(Error::ImageTooLarge {
+                needed: 0x1100,
+                available: 0x1000
+            })
+        ));
+
+        let stm32 = BinInfoResponse {
+            family_id: Some(FamilyId::STM32F401),
+            ..bininfo
+        };
+        assert!(stm32.check_fits(0x0800_0800, 0x800).is_ok());
+        assert!(stm32.check_fits(0x0800_0800, 0x900).is_err());
+    }
+
+    #[test]
+    fn check_segments_alignment_and_overlap() {
+        let bininfo = BinInfoResponse {
+            mode: crate::BinInfoMode::Bootloader,
+            flash_page_size: 256,
+            flash_num_pages: 64,
+            max_message_size: 512,
+            family_id: None,
+        };
+
+        //out of order with a gap is fine
+        assert!(bininfo
+            .check_segments(&[(0x2000, vec![0; 0x300]), (0x0400, vec![0; 4])])
+            .is_ok());
+
+        assert!(matches!(
+            bininfo.check_segments(&[(0x0410, vec![0; 4])]),
+            Err(Error::UnalignedAddress {
+                address: 0x0410,
+                page_size: 256
+            })
+        ));
+
+        //the padded last page of the first runs into the second
+        match bininfo.check_segments(&[(0x0400, vec![0; 0x180]), (0x0500, vec![0; 4])]) {
+            Err(Error::OverlappingSegments { a, b }) => {
+                assert_eq!(a, 0x0400..0x0600);
+                assert_eq!(b, 0x0500..0x0600);
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+    #[test]
+    fn check_overlap_shared_page() {
+        let bininfo = BinInfoResponse {
+            mode: crate::BinInfoMode::Bootloader,
+            flash_page_size: 256,
+            flash_num_pages: 64,
+            max_message_size: 512,
+            family_id: None,
+        };
+
+        //the first ends right where the second's page starts
+        assert!(bininfo
+            .check_overlap(&[(0x0400, vec![0; 0x100]), (0x0500, vec![0; 4])])
+            .is_ok());
+
+        //one byte over the boundary and both write page 0x0500
+        match bininfo.check_overlap(&[(0x0500, vec![0; 4]), (0x0400, vec![0; 0x101])]) {
+            Err(Error::OverlappingSegments { a, b }) => {
+                assert_eq!(a, 0x0400..0x0600);
+                assert_eq!(b, 0x0500..0x0600);
+            }
+            result => panic!("{:?}", result),
+        }
+
+        //unaligned segments are widened to the pages they touch
+        assert!(matches!(
+            bininfo.check_overlap(&[(0x0400, vec![0; 4]), (0x04F0, vec![0; 4])]),
+            Err(Error::OverlappingSegments { .. })
+        ));
+    }
+}
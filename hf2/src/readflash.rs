@@ -0,0 +1,174 @@
+use crate::{bin_info, read_words, BinInfoResponse, Error, ReadWrite};
+use alloc::vec::Vec;
+
+///Largest flash size in bytes read_flash will accept from bininfo before assuming the device is reporting garbage.
+pub const MAX_FLASH_SIZE: u32 = 16 * 1024 * 1024;
+
+///Read the entire flash into a Vec, starting at address 0. The size is taken from bin_info and the reads are chunked to fit max_message_size.
+pub fn read_flash(d: &impl ReadWrite) -> Result<Vec<u8>, Error> {
+    read_flash_with_progress(d, |_, _| {})
+}
+
+///Same as read_flash, calling progress with the number of bytes read so far and the total after every chunk.
+pub fn read_flash_with_progress<F>(d: &impl ReadWrite, progress: F) -> Result<Vec<u8>, Error>
+where
+    F: FnMut(usize, usize),
+{
+    let bininfo = bin_info(d)?;
+    let size = flash_size(&bininfo)?;
+    let words_per_read = max_words_per_read(&bininfo)?;
+
+    read_chunked(d, 0, size, words_per_read, progress)
+}
+
+///Read length bytes of memory starting at address via chunked read_words. The address must be word aligned, a length that isn't is read up to the next whole word and truncated. words_per_request defaults to max_words_per_read and may not exceed it.
+pub fn read_memory(
+    d: &impl ReadWrite,
+    address: u32,
+    length: u32,
+    words_per_request: Option<u32>,
+) -> Result<Vec<u8>, Error> {
+    let bininfo = bin_info(d)?;
+    let max = max_words_per_read(&bininfo)?;
+
+    let words_per_read = match words_per_request {
+        None => max,
+        Some(words) if words > 0 && words <= max => words,
+        Some(_) => return Err(Error::Arguments),
+    };
+
+    let aligned = length.checked_add(3).ok_or(Error::Arguments)? & !0x3;
+
+    let mut memory = read_chunked(d, address, aligned, words_per_read, |_, _| {})?;
+    memory.truncate(length as usize);
+    Ok(memory)
+}
+
+fn read_chunked<F>(
+    d: &impl ReadWrite,
+    address: u32,
+    length: u32,
+    words_per_read: u32,
+    mut progress: F,
+) -> Result<Vec<u8>, Error>
+where
+    F: FnMut(usize, usize),
+{
+    if address & 0x3 != 0 || length & 0x3 != 0 {
+        return Err(Error::Arguments);
+    }
+    //so address + offset below can't wrap
+    if address.checked_add(length).is_none() {
+        return Err(Error::Arguments);
+    }
+
+    let mut memory: Vec<u8> = Vec::with_capacity(length as usize);
+
+    for offset in (0..length).step_by(words_per_read as usize * 4) {
+        let target_address = address + offset;
+        let words_left = (length - offset) / 4;
+        let num_words = words_left.min(words_per_read);
+
+        let rsp = read_words(d, target_address, num_words)?;
+        if rsp.words.len() != num_words as usize {
+            log::debug!(
+                "read_words @{:08X} returned {} words, expected {}",
+                target_address,
+                rsp.words.len(),
+                num_words
+            );
+            return Err(Error::Parse);
+        }
+
+        for word in rsp.words {
+            memory.extend_from_slice(&word.to_le_bytes());
+        }
+
+        progress(memory.len(), length as usize);
+    }
+
+    Ok(memory)
+}
+
+///Total flash size in bytes as reported by bininfo, rejecting overflowing or implausibly large values.
+pub(crate) fn flash_size(bininfo: &BinInfoResponse) -> Result<u32, Error> {
+    match bininfo.flash_page_size.checked_mul(bininfo.flash_num_pages) {
+        Some(size) if size <= MAX_FLASH_SIZE && size % 4 == 0 => Ok(size),
+        _ => Err(Error::Parse),
+    }
+}
+
+///Number of words that fit in a single read_words response after the 4 byte response header.
+pub fn max_words_per_read(bininfo: &BinInfoResponse) -> Result<u32, Error> {
+    match bininfo.max_message_size.saturating_sub(4) / 4 {
+        0 => Err(Error::Parse),
+        words => Ok(words),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BinInfoMode;
+    use alloc::vec;
+
+    fn bininfo(flash_page_size: u32, flash_num_pages: u32) -> BinInfoResponse {
+        BinInfoResponse {
+            mode: BinInfoMode::Bootloader,
+            flash_page_size,
+            flash_num_pages,
+            max_message_size: 256,
+            family_id: None,
+        }
+    }
+
+    #[test]
+    fn max_words() {
+        assert_eq!(max_words_per_read(&bininfo(512, 1024)).unwrap(), 63);
+    }
+
+    #[test]
+    fn flash_size_plausible() {
+        assert_eq!(flash_size(&bininfo(512, 1024)).unwrap(), 512 * 1024);
+    }
+
+    #[test]
+    fn read_memory_truncates_unaligned_length() {
+        let mock = crate::mock::Mock::default();
+        let mut bininfo = vec![];
+        for word in &[1_u32, 512, 1024, 256] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+        mock.push_response(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let memory = read_memory(&mock, 0x2000, 6, None).unwrap();
+
+        assert_eq!(memory, vec![1, 2, 3, 4, 5, 6]);
+        //asked for two whole words
+        assert_eq!(mock.writes.borrow()[1][14], 2);
+    }
+
+    #[test]
+    fn read_memory_past_address_space() {
+        let mock = crate::mock::Mock::default();
+        let mut bininfo = vec![];
+        for word in &[1_u32, 512, 1024, 256] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+
+        assert!(matches!(
+            read_memory(&mock, 0xFFFF_FFF0, 0x20, None),
+            Err(Error::Arguments)
+        ));
+        //refused before a single read_words
+        assert_eq!(mock.writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn flash_size_implausible() {
+        assert!(flash_size(&bininfo(512, 0xFFFF_FFFF)).is_err());
+        assert!(flash_size(&bininfo(4096, 8192)).is_err());
+    }
+}
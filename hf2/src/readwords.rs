@@ -1,10 +1,11 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
+use alloc::{vec, vec::Vec};
 use scroll::{ctx, Pread, Pwrite, LE};
 
 ///Read a number of words from memory. Memory is read word by word (and not byte by byte), and target_addr must be suitably aligned. This is to support reading of special IO regions.
 pub fn read_words(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     num_words: u32,
 ) -> Result<ReadWordsResponse, Error> {
@@ -14,17 +15,22 @@ pub fn read_words(
     buffer.gwrite_with(target_address, &mut offset, scroll::LE)?;
     buffer.gwrite_with(num_words, &mut offset, scroll::LE)?;
 
-    xmit(Command::new(0x0008, 0, buffer), d)?;
+    let rsp = send(&Command::new(0x0008, buffer), d)?;
 
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => return (data.as_slice()).pread_with(0, LE),
-        Ok(_) => return Err(Error::CommandNotRecognized),
-        Err(e) => return Err(e),
+    rsp.data.as_slice().pread_with(0, LE)
+}
+
+///Read the single word at target_address, which has to be word aligned, otherwise Error::Arguments without asking the device.
+pub fn read_word(d: &impl ReadWrite, target_address: u32) -> Result<u32, Error> {
+    if !target_address.is_multiple_of(4) {
+        return Err(Error::Arguments);
     }
+
+    read_words(d, target_address, 1)?
+        .words
+        .first()
+        .copied()
+        .ok_or(Error::Parse)
 }
 
 ///Response to the read_words command
@@ -48,3 +54,52 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for ReadWordsResponse {
         Ok((ReadWordsResponse { words }, offset))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+
+    #[test]
+    fn read_words_packs_and_parses() {
+        let mock = Mock::default();
+        mock.push_response(&[0x78, 0x56, 0x34, 0x12, 0xEF, 0xBE, 0xAD, 0xDE]);
+
+        let rsp = read_words(&mock, 0x2000_0000, 2).unwrap();
+
+        assert_eq!(rsp.words, vec![0x1234_5678, 0xDEAD_BEEF]);
+
+        //report id, final packet header, command id, tag, reserved, then LE address and count
+        let tag = mock.tag();
+        let writes = mock.writes.borrow();
+        assert_eq!(
+            writes[0],
+            vec![
+                0x00, 0x50, 0x08, 0x00, 0x00, 0x00, tag[0], tag[1], 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x20, 0x02, 0x00, 0x00, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn read_word_checks_alignment() {
+        let mock = Mock::default();
+        mock.push_response(&[0xEF, 0xBE, 0xAD, 0xDE]);
+
+        assert!(matches!(
+            read_word(&mock, 0x2000_0002),
+            Err(Error::Arguments)
+        ));
+        assert!(mock.writes.borrow().is_empty());
+
+        assert_eq!(read_word(&mock, 0x2000_0004).unwrap(), 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn parse_rejects_empty() {
+        let data: Vec<u8> = vec![];
+        assert!((data.as_slice())
+            .pread_with::<ReadWordsResponse>(0, LE)
+            .is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use crate::command::{xmit, Command};
-use crate::Error;
+use crate::{Error, ReadWrite};
+use alloc::vec;
 
 ///Reset the device into bootloader, usually for flashing. Empty tuple response.
-pub fn reset_into_bootloader(d: &hidapi::HidDevice) -> Result<(), Error> {
-    xmit(Command::new(0x0004, 0, vec![]), d)
+pub fn reset_into_bootloader(d: &impl ReadWrite) -> Result<(), Error> {
+    xmit(&Command::new(0x0004, vec![]), d)
 }
@@ -0,0 +1,24 @@
+use crate::Error;
+use alloc::{vec, vec::Vec};
+
+///Sort chunks by address and join the ones that touch into contiguous (address, data) segments. Overlapping chunks are an error.
+pub(crate) fn merge_segments(mut chunks: Vec<(u32, &[u8])>) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    chunks.sort_by_key(|(address, _)| *address);
+
+    let mut segments: Vec<(u32, Vec<u8>)> = vec![];
+    for (address, chunk) in chunks {
+        if let Some((start, data)) = segments.last_mut() {
+            let end = u64::from(*start) + data.len() as u64;
+            if u64::from(address) == end {
+                data.extend_from_slice(chunk);
+                continue;
+            }
+            if u64::from(address) < end {
+                return Err(Error::Parse);
+            }
+        }
+        segments.push((address, chunk.to_vec()));
+    }
+
+    Ok(segments)
+}
@@ -0,0 +1,263 @@
+use crate::{Error, ReadWrite};
+use serialport::SerialPort;
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Duration;
+
+///Line rate the port is opened at. USB CDC ignores it, anything but the 1200 baud touch that resets many boards into their bootloader will do.
+pub const BAUD_RATE: u32 = 115_200;
+
+///How long hf2_read waits for a packet, the same second hidapi reads wait.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+///Bootloaders speaking hf2 over a USB CDC serial port rather than HID. Packets are framed the same, a header byte and up to 63 bytes of payload, but sent as they are, without a report id or padding to a whole report.
+pub struct SerialTransport {
+    port: RefCell<Box<dyn SerialPort>>,
+}
+
+impl SerialTransport {
+    ///Open the port at path, COM3 or /dev/ttyACM0 say.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let port = serialport::new(path, BAUD_RATE)
+            .timeout(READ_TIMEOUT)
+            .open()?;
+        Ok(Self::new(port))
+    }
+
+    ///Use an already opened port, whatever its settings.
+    pub fn new(port: Box<dyn SerialPort>) -> Self {
+        SerialTransport {
+            port: RefCell::new(port),
+        }
+    }
+
+    fn read_packet(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let mut port = self.port.borrow_mut();
+        port.set_timeout(timeout)?;
+
+        match port.read(&mut buf[..1]) {
+            Ok(0) => return Ok(0),
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::TimedOut => return Ok(0),
+            Err(e) => return Err(e.into()),
+        }
+
+        //as much as the header says follows, rx reports a header claiming more than buf holds
+        let len = ((buf[0] & 0x3F) as usize).min(buf.len() - 1);
+        match port.read_exact(&mut buf[1..=len]) {
+            Ok(()) => Ok(len + 1),
+            //what's left of the packet may show up later, by then nothing can tell it from the next header
+            Err(e) if e.kind() == ErrorKind::TimedOut => Err(Error::Timeout),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl ReadWrite for SerialTransport {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        let mut port = self.port.borrow_mut();
+
+        //the first byte is the hid report id, serial has no use for it
+        port.write_all(data.get(1..).unwrap_or_default())?;
+        port.flush()?;
+        Ok(data.len())
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.read_packet(buf, READ_TIMEOUT)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.read_packet(buf, timeout)
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(err: serialport::Error) -> Self {
+        Error::Transmission(Box::new(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+    use serialport::{ClearBuffer, DataBits, FlowControl, Parity, StopBits};
+    use std::sync::Mutex;
+
+    ///A port that reads back rx, timing out once it's empty, and keeps everything written to it in tx.
+    struct FakePort {
+        rx: VecDeque<u8>,
+        tx: Arc<Mutex<Vec<u8>>>,
+        timeout: Duration,
+    }
+
+    fn transport(rx: &[u8]) -> (SerialTransport, Arc<Mutex<Vec<u8>>>) {
+        let tx = Arc::new(Mutex::new(vec![]));
+        let port = FakePort {
+            rx: rx.iter().copied().collect(),
+            tx: tx.clone(),
+            timeout: READ_TIMEOUT,
+        };
+        (SerialTransport::new(Box::new(port)), tx)
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.rx.is_empty() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let len = buf.len().min(self.rx.len());
+            for (byte, rx) in buf.iter_mut().zip(self.rx.drain(..len)) {
+                *byte = rx;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.tx.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    //only the timeout matters to SerialTransport, the rest are never called
+    impl SerialPort for FakePort {
+        fn name(&self) -> Option<String> {
+            None
+        }
+        fn baud_rate(&self) -> serialport::Result<u32> {
+            Ok(BAUD_RATE)
+        }
+        fn data_bits(&self) -> serialport::Result<DataBits> {
+            Ok(DataBits::Eight)
+        }
+        fn flow_control(&self) -> serialport::Result<FlowControl> {
+            Ok(FlowControl::None)
+        }
+        fn parity(&self) -> serialport::Result<Parity> {
+            Ok(Parity::None)
+        }
+        fn stop_bits(&self) -> serialport::Result<StopBits> {
+            Ok(StopBits::One)
+        }
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+        fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+        fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+            Ok(false)
+        }
+        fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+            Ok(true)
+        }
+        fn bytes_to_read(&self) -> serialport::Result<u32> {
+            Ok(self.rx.len() as u32)
+        }
+        fn bytes_to_write(&self) -> serialport::Result<u32> {
+            Ok(0)
+        }
+        fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+            unimplemented!()
+        }
+        fn set_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+        fn clear_break(&self) -> serialport::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_drops_report_id() {
+        let (serial, tx) = transport(&[]);
+
+        assert_eq!(serial.hf2_write(&[0, 0x43, 1, 2, 3]).unwrap(), 5);
+        assert_eq!(*tx.lock().unwrap(), vec![0x43, 1, 2, 3]);
+    }
+
+    #[test]
+    fn read_one_packet_at_a_time() {
+        //two packets back to back, the header's low 6 bits are the payload length
+        let (serial, _) = transport(&[0x43, 1, 2, 3, 0x81, 9]);
+        let mut buf = [0; 64];
+
+        assert_eq!(serial.hf2_read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &[0x43, 1, 2, 3]);
+        assert_eq!(serial.hf2_read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[0x81, 9]);
+    }
+
+    #[test]
+    fn read_nothing_is_empty() {
+        let (serial, _) = transport(&[]);
+        let mut buf = [0; 64];
+
+        assert_eq!(serial.hf2_read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_short_packet_times_out() {
+        //header says 5 bytes follow, only 2 ever arrive
+        let (serial, _) = transport(&[0x45, 1, 2]);
+        let mut buf = [0; 64];
+
+        assert!(matches!(serial.hf2_read(&mut buf), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn read_header_past_buf() {
+        let (serial, _) = transport(&[0x3F, 1, 2, 3, 4, 5]);
+        let mut buf = [0; 4];
+
+        assert_eq!(serial.hf2_read(&mut buf).unwrap(), 4);
+        assert_eq!(buf, [0x3F, 1, 2, 3]);
+    }
+
+    #[test]
+    fn read_timeout_is_set() {
+        let (serial, _) = transport(&[0x40]);
+        let mut buf = [0; 64];
+
+        serial
+            .hf2_read_timeout(&mut buf, Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(serial.port.borrow().timeout(), Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,83 @@
+use alloc::string::String;
+use core::fmt::Write;
+
+///Data bytes per S3 record, the same as objcopy emits.
+pub const SREC_BYTES_PER_RECORD: usize = 16;
+
+///Encode data read from address as Motorola S-records, an S0 header, S3 data records with 32 bit addresses, an S5 (or S6 past 0xFFFF records) count and an S7 termination.
+pub fn to_srec(address: u32, data: &[u8]) -> String {
+    let mut srec = String::new();
+
+    record(&mut srec, '0', &[0, 0], b"hf2");
+
+    let mut count = 0_u32;
+    for (index, chunk) in data.chunks(SREC_BYTES_PER_RECORD).enumerate() {
+        let record_address = address + (index * SREC_BYTES_PER_RECORD) as u32;
+        record(&mut srec, '3', &record_address.to_be_bytes(), chunk);
+        count += 1;
+    }
+
+    if count <= 0xFFFF {
+        record(&mut srec, '5', &count.to_be_bytes()[2..], &[]);
+    } else {
+        record(&mut srec, '6', &count.to_be_bytes()[1..], &[]);
+    }
+
+    //no entry point to speak of in a dump
+    record(&mut srec, '7', &[0, 0, 0, 0], &[]);
+
+    srec
+}
+
+//S<kind><count><address><data><checksum>, count covers address, data and checksum
+fn record(srec: &mut String, kind: char, address: &[u8], data: &[u8]) {
+    let count = (address.len() + data.len() + 1) as u8;
+
+    let mut sum = count;
+    write!(srec, "S{}{:02X}", kind, count).unwrap();
+    for byte in address.iter().chain(data) {
+        sum = sum.wrapping_add(*byte);
+        write!(srec, "{:02X}", byte).unwrap();
+    }
+    writeln!(srec, "{:02X}", !sum).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn reference() {
+        let srec = to_srec(0x4000, &[0x01, 0x02, 0x03, 0x04]);
+
+        assert_eq!(
+            srec,
+            "S0060000686632F9\nS3090000400001020304AC\nS5030001FB\nS70500000000FA\n"
+        );
+    }
+
+    #[test]
+    fn splits_records() {
+        let data: Vec<u8> = (0..40).collect();
+        let srec = to_srec(0x0800_0000, &data);
+        let lines: Vec<&str> = srec.lines().collect();
+
+        //header, 16 + 16 + 8 data bytes, count, termination
+        assert_eq!(lines.len(), 6);
+        assert!(lines[1].starts_with("S3150800000000"));
+        assert!(lines[2].starts_with("S3150800001010"));
+        assert!(lines[3].starts_with("S30D0800002020"));
+        assert_eq!(lines[4], "S5030003F9");
+
+        //every record sums to 0xFF including its checksum
+        for line in lines {
+            let bytes: Vec<u8> = (2..line.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                .collect();
+            let sum = bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+            assert_eq!(sum, 0xFF, "{}", line);
+        }
+    }
+}
@@ -1,9 +1,49 @@
-use crate::command::{rx, xmit, Command};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{Error, ReadWrite};
+use alloc::vec;
 
 /// When issued in bootloader mode, it has no effect. In user-space mode it causes handover to bootloader. A BININFO command can be issued to verify that. Empty tuple response.
-pub fn start_flash(d: &hidapi::HidDevice) -> Result<(), Error> {
-    xmit(Command::new(0x0005, 0, vec![]), d)?;
+pub fn start_flash(d: &impl ReadWrite) -> Result<(), Error> {
+    send(&Command::new(0x0005, vec![]), d).map(|_| ())
+}
+
+///start_flash, except that a bootloader which doesn't understand it only gets a warning logged, page writes still work on those. Any other failure is returned.
+pub fn start_flash_if_supported(d: &impl ReadWrite) -> Result<(), Error> {
+    match start_flash(d) {
+        Err(e) if e.is_unsupported() => {
+            log::warn!("device doesn't support START_FLASH, writing anyway");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+    use crate::CommandResponseStatus;
+
+    #[test]
+    fn start_flash_not_understood() {
+        let mock = Mock::default();
+        mock.push_failure(CommandResponseStatus::ParseError, 0);
+
+        start_flash_if_supported(&mock).unwrap();
+    }
+
+    #[test]
+    fn start_flash_failing() {
+        let mock = Mock::default();
+        mock.push_failure(CommandResponseStatus::ExecutionError, 0);
 
-    rx(d).map(|_| ())
+        assert!(matches!(
+            start_flash_if_supported(&mock),
+            Err(Error::Status {
+                command: 0x0005,
+                status: CommandResponseStatus::ExecutionError,
+                ..
+            })
+        ));
+    }
 }
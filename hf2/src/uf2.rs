@@ -0,0 +1,162 @@
+use crate::segments::merge_segments;
+use crate::{Error, FamilyId};
+use alloc::{vec, vec::Vec};
+use scroll::{Pread, LE};
+
+///Every uf2 block is this long, a 32 byte header, up to 476 bytes of payload and the end magic.
+pub const UF2_BLOCK_SIZE: usize = 512;
+pub const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+pub const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+pub const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+
+///most payload a single block can carry
+const UF2_MAX_PAYLOAD: usize = 476;
+///block isn't meant for main flash, for example comments or a file container
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+///the file size field holds a family id instead
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+///A uf2 file with its blocks merged into contiguous segments.
+#[derive(Debug, PartialEq)]
+pub struct Uf2 {
+    ///family id the blocks were built for, if they say
+    pub family_id: Option<FamilyId>,
+    ///(address, data) in address order, no two touch or overlap
+    pub segments: Vec<(u32, Vec<u8>)>,
+}
+
+///Parse a uf2 file into the segments it would write to main flash. Files carrying images for more than one family, or with overlapping blocks, are rejected.
+pub fn parse_uf2(file: &[u8]) -> Result<Uf2, Error> {
+    if !file.len().is_multiple_of(UF2_BLOCK_SIZE) {
+        return Err(Error::Parse);
+    }
+
+    let mut family_id = None;
+    let mut blocks = vec![];
+
+    for block in file.chunks(UF2_BLOCK_SIZE) {
+        let mut offset = 0;
+        let magic_start0: u32 = block.gread_with(&mut offset, LE)?;
+        let magic_start1: u32 = block.gread_with(&mut offset, LE)?;
+        let flags: u32 = block.gread_with(&mut offset, LE)?;
+        let target_address: u32 = block.gread_with(&mut offset, LE)?;
+        let payload_size: u32 = block.gread_with(&mut offset, LE)?;
+        let _block_no: u32 = block.gread_with(&mut offset, LE)?;
+        let _num_blocks: u32 = block.gread_with(&mut offset, LE)?;
+        let file_size_or_family: u32 = block.gread_with(&mut offset, LE)?;
+        let magic_end: u32 = block.pread_with(UF2_BLOCK_SIZE - 4, LE)?;
+
+        if magic_start0 != UF2_MAGIC_START0
+            || magic_start1 != UF2_MAGIC_START1
+            || magic_end != UF2_MAGIC_END
+            || payload_size as usize > UF2_MAX_PAYLOAD
+        {
+            return Err(Error::Parse);
+        }
+
+        if flags & FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+
+        if flags & FLAG_FAMILY_ID_PRESENT != 0 {
+            let block_family = FamilyId::from(file_size_or_family);
+            match family_id {
+                Some(family_id) if family_id != block_family => return Err(Error::Parse),
+                _ => family_id = Some(block_family),
+            }
+        }
+
+        blocks.push((
+            target_address,
+            &block[offset..offset + payload_size as usize],
+        ));
+    }
+
+    let segments = merge_segments(blocks)?;
+
+    Ok(Uf2 {
+        family_id,
+        segments,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(flags: u32, address: u32, payload: &[u8], family: u32) -> Vec<u8> {
+        let mut block = vec![];
+        for word in &[
+            UF2_MAGIC_START0,
+            UF2_MAGIC_START1,
+            flags,
+            address,
+            payload.len() as u32,
+            0,
+            1,
+            family,
+        ] {
+            block.extend_from_slice(&word.to_le_bytes());
+        }
+        block.extend_from_slice(payload);
+        block.resize(UF2_BLOCK_SIZE - 4, 0);
+        block.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn merges_contiguous_blocks() {
+        let mut file = vec![];
+        //out of order on purpose
+        file.extend(block(
+            FLAG_FAMILY_ID_PRESENT,
+            0x2100,
+            &[3; 256],
+            0x6800_0000,
+        ));
+        file.extend(block(
+            FLAG_FAMILY_ID_PRESENT,
+            0x2000,
+            &[2; 256],
+            0x6800_0000,
+        ));
+        file.extend(block(FLAG_FAMILY_ID_PRESENT, 0x8000, &[4; 4], 0x6800_0000));
+        file.extend(block(FLAG_NOT_MAIN_FLASH, 0x0, &[5; 4], 0));
+
+        let uf2 = parse_uf2(&file).unwrap();
+
+        assert_eq!(uf2.family_id, Some(FamilyId::UNKNOWN(0x6800_0000)));
+        assert_eq!(uf2.segments.len(), 2);
+        assert_eq!(uf2.segments[0].0, 0x2000);
+        assert_eq!(uf2.segments[0].1.len(), 512);
+        assert_eq!(uf2.segments[0].1[256], 3);
+        assert_eq!(uf2.segments[1], (0x8000, vec![4; 4]));
+    }
+
+    #[test]
+    fn family_id_is_optional() {
+        let uf2 = parse_uf2(&block(0, 0x2000, &[1; 256], 0x4000)).unwrap();
+
+        assert_eq!(uf2.family_id, None);
+        assert_eq!(uf2.segments, vec![(0x2000, vec![1; 256])]);
+    }
+
+    #[test]
+    fn rejects_bad_blocks() {
+        let mut bad_magic = block(0, 0x2000, &[1; 256], 0);
+        bad_magic[UF2_BLOCK_SIZE - 1] = 0;
+        assert!(parse_uf2(&bad_magic).is_err());
+
+        let mut truncated = block(0, 0x2000, &[1; 256], 0);
+        truncated.pop();
+        assert!(parse_uf2(&truncated).is_err());
+
+        let mut overlapping = block(0, 0x2000, &[1; 256], 0);
+        overlapping.extend(block(0, 0x2080, &[1; 256], 0));
+        assert!(parse_uf2(&overlapping).is_err());
+
+        let mut mixed = block(FLAG_FAMILY_ID_PRESENT, 0x2000, &[1; 256], 1);
+        mixed.extend(block(FLAG_FAMILY_ID_PRESENT, 0x2100, &[1; 256], 2));
+        assert!(parse_uf2(&mixed).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+///The architectural SRAM region of the Cortex-M memory map, a plausible home for any initial stack pointer.
+pub const CORTEX_M_SRAM: Range<u32> = 0x2000_0000..0x4000_0000;
+
+///Something wrong with the vector table at the start of an image.
+#[derive(Debug, PartialEq)]
+pub enum VectorProblem {
+    ///image is shorter than the two vectors
+    TooShort,
+    ///initial stack pointer outside ram or not word aligned
+    StackPointer(u32),
+    ///reset handler outside the flash being written
+    ResetHandler(u32),
+    ///reset handler is even, cortex-m only executes thumb
+    NotThumb(u32),
+}
+
+impl core::fmt::Display for VectorProblem {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            VectorProblem::TooShort => write!(f, "image too short for a vector table"),
+            VectorProblem::StackPointer(sp) => write!(
+                f,
+                "initial stack pointer 0x{:08X} isn't a word aligned ram address",
+                sp
+            ),
+            VectorProblem::ResetHandler(pc) => write!(
+                f,
+                "reset handler 0x{:08X} is outside the flash being written",
+                pc
+            ),
+            VectorProblem::NotThumb(pc) => {
+                write!(f, "reset handler 0x{:08X} is even, not a thumb address", pc)
+            }
+        }
+    }
+}
+
+///Check the first two words of a Cortex-M image, the initial stack pointer and the reset handler, point into ram and into flash respectively. Returns every problem found, empty if the image looks plausible.
+pub fn check_cortex_m_vectors(
+    image: &[u8],
+    ram: Range<u32>,
+    flash: Range<u32>,
+) -> Vec<VectorProblem> {
+    if image.len() < 8 {
+        return vec![VectorProblem::TooShort];
+    }
+
+    let sp = u32::from_le_bytes([image[0], image[1], image[2], image[3]]);
+    let pc = u32::from_le_bytes([image[4], image[5], image[6], image[7]]);
+
+    let mut problems = vec![];
+
+    //the stack grows down from the top so the end of ram itself is fine
+    if sp & 0x3 != 0 || sp <= ram.start || sp > ram.end {
+        problems.push(VectorProblem::StackPointer(sp));
+    }
+
+    if !flash.contains(&(pc & !1)) {
+        problems.push(VectorProblem::ResetHandler(pc));
+    }
+
+    if pc & 1 == 0 {
+        problems.push(VectorProblem::NotThumb(pc));
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(sp: u32, pc: u32) -> Vec<u8> {
+        let mut image = sp.to_le_bytes().to_vec();
+        image.extend_from_slice(&pc.to_le_bytes());
+        image.resize(0x400, 0);
+        image
+    }
+
+    #[test]
+    fn plausible() {
+        let image = image(0x2003_0000, 0x0000_4101);
+        let problems = check_cortex_m_vectors(&image, CORTEX_M_SRAM, 0x4000..0x4400);
+
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn mislinked() {
+        //linked for address 0 but written at 0x4000, with an erased stack pointer
+        let image = image(0xFFFF_FFFF, 0x0000_0100);
+        let problems = check_cortex_m_vectors(&image, CORTEX_M_SRAM, 0x4000..0x4400);
+
+        assert_eq!(
+            problems,
+            vec![
+                VectorProblem::StackPointer(0xFFFF_FFFF),
+                VectorProblem::ResetHandler(0x0000_0100),
+                VectorProblem::NotThumb(0x0000_0100),
+            ]
+        );
+    }
+
+    #[test]
+    fn too_short() {
+        assert_eq!(
+            check_cortex_m_vectors(&[0; 4], CORTEX_M_SRAM, 0..4),
+            vec![VectorProblem::TooShort]
+        );
+    }
+}
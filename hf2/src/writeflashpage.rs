@@ -1,22 +1,217 @@
-use crate::command::{rx, xmit, Command};
-use crate::Error;
-use scroll::Pwrite;
+use crate::command::{send, Command};
+use crate::{BinInfoResponse, Error, ReadWrite};
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
 
-///Write a single page of flash memory. Empty tuple response.
+///command id, tag and reserved bytes, then the target address
+const WRITE_FLASH_PAGE_HEADER: u32 = 12;
+
+///Write a single page of flash memory. Empty tuple response. Takes the page borrowed or owned.
 pub fn write_flash_page(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
-    data: Vec<u8>,
+    data: impl AsRef<[u8]>,
 ) -> Result<(), Error> {
-    let mut buffer = vec![0_u8; data.len() + 4];
-    let mut offset = 0;
+    write_flash_pages(d, target_address, data.as_ref())
+}
+
+///Write consecutive pages of flash memory from target_address in a single message, data being a whole number of pages that fits max_message_size. This isn't in the hf2 spec, bootloaders that only take a single page reply with a status error, see PageWriter.
+pub fn write_flash_pages(
+    d: &impl ReadWrite,
+    target_address: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    write_flash_pages_with(d, target_address, data, &mut vec![])
+}
+
+///write_flash_pages building the command's data in buffer, so a caller writing page after page allocates it once.
+fn write_flash_pages_with(
+    d: &impl ReadWrite,
+    target_address: u32,
+    data: &[u8],
+    buffer: &mut Vec<u8>,
+) -> Result<(), Error> {
+    buffer.clear();
+    buffer.extend_from_slice(&target_address.to_le_bytes());
+    buffer.extend_from_slice(data);
+
+    send(&Command::new(0x0006, buffer.as_slice()), d).map(|_| ())
+}
+
+///Most whole pages a single write_flash_pages message has room for, at least 1.
+pub fn max_write_pages(bininfo: &BinInfoResponse) -> u32 {
+    bininfo
+        .max_message_size
+        .saturating_sub(WRITE_FLASH_PAGE_HEADER)
+        .checked_div(bininfo.flash_page_size)
+        .unwrap_or(0)
+        .max(1)
+}
 
-    buffer.gwrite_with(target_address, &mut offset, scroll::LE)?;
-    for i in &data {
-        buffer.gwrite_with(i, &mut offset, scroll::LE)?;
+///Runs of consecutive changed pages as ranges of page indexes, none longer than max_pages.
+pub fn changed_runs(changed: &[bool], max_pages: u32) -> Vec<Range<usize>> {
+    let max_pages = max_pages.max(1) as usize;
+    let mut runs: Vec<Range<usize>> = vec![];
+
+    for (index, _) in changed.iter().enumerate().filter(|(_, changed)| **changed) {
+        match runs.last_mut() {
+            Some(run) if run.end == index && run.len() < max_pages => run.end += 1,
+            _ => runs.push(index..index + 1),
+        }
+    }
+
+    runs
+}
+
+///Writes runs of pages in as few messages as the device takes. Once the device rejects a batched write every page after is written in a message of its own.
+#[derive(Debug, Clone)]
+pub struct PageWriter {
+    page_size: u32,
+    max_pages: u32,
+    messages: u32,
+    buffer: Vec<u8>,
+}
+
+impl PageWriter {
+    ///Up to max_pages pages per message, 1 writes a page at a time just like write_flash_page.
+    pub fn new(page_size: u32, max_pages: u32) -> Self {
+        PageWriter {
+            page_size,
+            max_pages: max_pages.max(1),
+            messages: 0,
+            buffer: vec![],
+        }
+    }
+
+    ///Pages per message from now on, 1 after the device rejected a batch.
+    pub fn max_pages(&self) -> u32 {
+        self.max_pages
     }
 
-    xmit(Command::new(0x0006, 0, buffer), d)?;
+    ///Messages sent so far.
+    pub fn messages(&self) -> u32 {
+        self.messages
+    }
+
+    ///Write data, a whole number of pages, from target_address on.
+    pub fn write(
+        &mut self,
+        d: &impl ReadWrite,
+        target_address: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let page_size = self.page_size as usize;
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let len = (page_size * self.max_pages as usize).min(data.len() - offset);
+            let address = target_address + offset as u32;
 
-    rx(d).map(|_| ())
+            let pages = &data[offset..offset + len];
+            match write_flash_pages_with(d, address, pages, &mut self.buffer) {
+                Err(e @ Error::Status { .. }) if len > page_size => {
+                    log::warn!(
+                        "device rejected {} pages in one write: {}, writing a page at a time",
+                        len / page_size,
+                        e
+                    );
+                    self.max_pages = 1;
+                    continue;
+                }
+                result => result?,
+            }
+
+            self.messages += 1;
+            offset += len;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::Mock;
+    use crate::CommandResponseStatus;
+
+    #[test]
+    fn write_flash_page_packs_request() {
+        let mock = Mock::default();
+        mock.push_response(&[]);
+
+        write_flash_page(&mock, 0x2000, vec![0xAA, 0xBB, 0xCC, 0xDD]).unwrap();
+
+        //report id, final packet header, command id, tag, reserved, then LE address and the page
+        let tag = mock.tag();
+        assert_eq!(
+            mock.writes.borrow()[0],
+            vec![
+                0x00, 0x50, 0x06, 0x00, 0x00, 0x00, tag[0], tag[1], 0x00, 0x00, 0x00, 0x20, 0x00,
+                0x00, 0xAA, 0xBB, 0xCC, 0xDD
+            ]
+        );
+    }
+
+    #[test]
+    fn max_write_pages_fits_message() {
+        let bininfo = BinInfoResponse {
+            mode: crate::BinInfoMode::Bootloader,
+            flash_page_size: 64,
+            flash_num_pages: 1024,
+            max_message_size: 268,
+            family_id: None,
+        };
+        assert_eq!(max_write_pages(&bininfo), 4);
+
+        let bininfo = BinInfoResponse {
+            max_message_size: 8,
+            ..bininfo
+        };
+        assert_eq!(max_write_pages(&bininfo), 1);
+    }
+
+    #[test]
+    fn changed_runs_split_at_max() {
+        let changed = [true, true, true, false, true, false, false, true, true];
+
+        assert_eq!(changed_runs(&changed, 2), vec![0..2, 2..3, 4..5, 7..9]);
+        assert_eq!(changed_runs(&changed, 1).len(), 6);
+    }
+
+    #[test]
+    fn page_writer_falls_back_to_single_pages() {
+        let mock = Mock::default();
+        //batch rejected, then two single pages, then a lone page
+        mock.push_failure(CommandResponseStatus::ParseError, 0);
+        mock.push_response(&[]);
+        mock.push_response(&[]);
+        mock.push_response(&[]);
+
+        let mut writer = PageWriter::new(4, 2);
+        writer.write(&mock, 0x2000, &[0xAA; 8]).unwrap();
+        writer.write(&mock, 0x2010, &[0xBB; 4]).unwrap();
+
+        assert_eq!(writer.max_pages(), 1);
+        assert_eq!(writer.messages(), 3);
+
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 4);
+        assert_eq!(writes[0].len(), 10 + 4 + 8);
+        assert_eq!(&writes[2][10..14], &0x2004_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn page_writer_batches() {
+        let mock = Mock::default();
+        mock.push_response(&[]);
+        mock.push_response(&[]);
+
+        let mut writer = PageWriter::new(4, 2);
+        writer.write(&mock, 0x2000, &[0xAA; 12]).unwrap();
+
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 2);
+        assert_eq!(&writes[1][10..14], &0x2008_u32.to_le_bytes());
+    }
 }
@@ -1,24 +1,194 @@
-use crate::command::{rx, xmit, Command};
-use crate::Error;
+use crate::command::{send, Command};
+use crate::{bin_info, read_words, BinInfoResponse, Error, ReadWrite};
+use alloc::vec;
 use scroll::Pwrite;
 
-///Dual of READ WORDS, with the same constraints. Empty tuple response.
-pub fn write_words(
-    d: &hidapi::HidDevice,
-    target_address: u32,
-    num_words: u32,
-    words: Vec<u32>,
-) -> Result<(), Error> {
+///Dual of READ WORDS, with the same constraints. Empty tuple response. words are split into as many commands as max_message_size requires. The caller is responsible for target_address being word aligned.
+pub fn write_words(d: &impl ReadWrite, target_address: u32, words: &[u32]) -> Result<(), Error> {
+    write_memory(d, target_address, words, None, false)
+}
+
+///Write a single word at target_address, which has to be word aligned, otherwise Error::Arguments without asking the device. Unlike write_words it doesn't need bininfo.
+pub fn write_word(d: &impl ReadWrite, target_address: u32, value: u32) -> Result<(), Error> {
+    if !target_address.is_multiple_of(4) {
+        return Err(Error::Arguments);
+    }
+
+    write_words_once(d, target_address, &[value])
+}
+
+//a single WRITE_WORDS command, [address, num_words, words..] LE
+fn write_words_once(d: &impl ReadWrite, target_address: u32, words: &[u32]) -> Result<(), Error> {
     let mut buffer = vec![0_u8; words.len() * 4 + 8];
     let mut offset = 0;
 
     buffer.gwrite_with(target_address, &mut offset, scroll::LE)?;
-    buffer.gwrite_with(num_words, &mut offset, scroll::LE)?;
+    buffer.gwrite_with(words.len() as u32, &mut offset, scroll::LE)?;
     for i in words {
-        buffer.gwrite_with(i, &mut offset, scroll::LE)?;
+        buffer.gwrite_with(*i, &mut offset, scroll::LE)?;
+    }
+
+    send(&Command::new(0x0009, buffer), d).map(|_| ())
+}
+
+///Number of words that fit in a single write_words command after the 8 byte command header and the address and count.
+pub fn max_words_per_write(bininfo: &BinInfoResponse) -> Result<u32, Error> {
+    match bininfo.max_message_size.saturating_sub(16) / 4 {
+        0 => Err(Error::Parse),
+        words => Ok(words),
+    }
+}
+
+///Same as write_words with an explicit number of words per command. words_per_request defaults to max_words_per_write and may not exceed it. With verify every command is followed by a read_words over what it wrote, a write to a protected or read only address can still answer success, Error::WriteVerifyFailed at the first word that reads back differently. Bulk writes that can do without it save the round trips.
+pub fn write_memory(
+    d: &impl ReadWrite,
+    address: u32,
+    words: &[u32],
+    words_per_request: Option<u32>,
+    verify: bool,
+) -> Result<(), Error> {
+    let bininfo = bin_info(d)?;
+    let max = max_words_per_write(&bininfo)?;
+
+    let words_per_write = match words_per_request {
+        None => max,
+        Some(words) if words > 0 && words <= max => words,
+        Some(_) => return Err(Error::Arguments),
+    };
+
+    for (index, chunk) in words.chunks(words_per_write as usize).enumerate() {
+        let target_address = address + (index * words_per_write as usize * 4) as u32;
+        write_words_once(d, target_address, chunk)?;
+
+        if verify {
+            let readback = read_words(d, target_address, chunk.len() as u32)?.words;
+            //words missing from the read back count as not reading back as written
+            let differs = chunk.iter().zip(&readback).position(|(a, b)| a != b);
+            if let Some(index) =
+                differs.or((readback.len() < chunk.len()).then_some(readback.len()))
+            {
+                return Err(Error::WriteVerifyFailed {
+                    address: target_address + index as u32 * 4,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BinInfoMode;
+
+    #[test]
+    fn max_words() {
+        let bininfo = BinInfoResponse {
+            mode: BinInfoMode::Bootloader,
+            flash_page_size: 512,
+            flash_num_pages: 1024,
+            max_message_size: 256,
+            family_id: None,
+        };
+
+        assert_eq!(max_words_per_write(&bininfo).unwrap(), 60);
+    }
+
+    #[test]
+    fn write_word_single_command() {
+        let mock = crate::mock::Mock::default();
+        mock.push_response(&[]);
+
+        assert!(matches!(
+            write_word(&mock, 0x2000_0001, 1),
+            Err(Error::Arguments)
+        ));
+        write_word(&mock, 0x2000_0000, 0xDEAD_BEEF).unwrap();
+
+        //report id, final packet header, command id, tag, reserved, then LE address, count and value
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 1);
+        assert_eq!(
+            writes[0][10..],
+            [0x00, 0x00, 0x00, 0x20, 0x01, 0x00, 0x00, 0x00, 0xEF, 0xBE, 0xAD, 0xDE]
+        );
+    }
+
+    #[test]
+    fn write_words_chunks() {
+        let mock = crate::mock::Mock::default();
+
+        //bootloader, 512 byte pages, 1024 pages, 32 byte messages fit 4 words
+        let mut bininfo = vec![];
+        for word in &[1_u32, 512, 1024, 32] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+        mock.push_response(&[]);
+        mock.push_response(&[]);
+
+        write_words(&mock, 0x2000_0000, &[1, 2, 3, 4, 5, 6]).unwrap();
+
+        let writes = mock.writes.borrow();
+        assert_eq!(writes.len(), 3);
+
+        //address and count follow the 8 byte command header
+        assert_eq!(&writes[1][10..18], &[0x00, 0x00, 0x00, 0x20, 4, 0, 0, 0]);
+        assert_eq!(writes[1].len(), 18 + 16);
+        assert_eq!(&writes[2][10..18], &[0x10, 0x00, 0x00, 0x20, 2, 0, 0, 0]);
+        assert_eq!(&writes[2][18..], &[5, 0, 0, 0, 6, 0, 0, 0]);
+    }
+
+    #[test]
+    fn write_memory_readback() {
+        let mock = crate::mock::Mock::default();
+
+        let mut bininfo = vec![];
+        for word in &[1_u32, 512, 1024, 64] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+        mock.push_response(&[]);
+        //the second word didn't stick
+        let mut readback = vec![];
+        for word in &[1_u32, 0, 3] {
+            readback.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&readback);
+
+        assert!(matches!(
+            write_memory(&mock, 0x2000_0000, &[1, 2, 3], None, true),
+            Err(Error::WriteVerifyFailed {
+                address: 0x2000_0004
+            })
+        ));
+        //bininfo, the write and the read back
+        assert_eq!(mock.writes.borrow().len(), 3);
     }
 
-    xmit(Command::new(0x0009, 0, buffer), d)?;
+    #[test]
+    fn write_memory_short_readback() {
+        let mock = crate::mock::Mock::default();
 
-    rx(d).map(|_| ())
+        let mut bininfo = vec![];
+        for word in &[1_u32, 512, 1024, 64] {
+            bininfo.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&bininfo);
+        mock.push_response(&[]);
+        //only two of the three words came back, both as written
+        let mut readback = vec![];
+        for word in &[1_u32, 2] {
+            readback.extend_from_slice(&word.to_le_bytes());
+        }
+        mock.push_response(&readback);
+
+        assert!(matches!(
+            write_memory(&mock, 0x2000_0000, &[1, 2, 3], None, true),
+            Err(Error::WriteVerifyFailed {
+                address: 0x2000_0008
+            })
+        ));
+    }
 }